@@ -0,0 +1,80 @@
+import json
+from argparse import ArgumentParser
+from time import time
+
+import numpy as np
+from PIL import Image
+
+import pillow_jxl
+
+
+def make_image(filename, megapixels, dtype):
+    arr = np.asarray(Image.open(filename).convert("RGB"))
+    h, w, _ = arr.shape
+    scale = max(1, int(np.ceil(np.sqrt(megapixels * 1e6 / (h * w)))))
+    arr = np.tile(arr, (scale, scale, 1))
+    if dtype == "uint16":
+        return arr.astype(np.uint16) * 257
+    if dtype == "float32":
+        return arr.astype(np.float32) / 255
+    return arr
+
+
+def bench(fn, repeat):
+    best = float("inf")
+    for _ in range(repeat):
+        t = time()
+        fn()
+        best = min(best, time() - t)
+    return best
+
+
+def main(args):
+    cases = {
+        "call": lambda dec, data: dec(data),
+        "decode_array": lambda dec, data: dec.decode_array(data),
+    }
+    decoders = {
+        "default": pillow_jxl.Decoder(),
+        "ordered": pillow_jxl.Decoder(dither="ordered"),
+        "error-diffusion": pillow_jxl.Decoder(dither="error-diffusion"),
+        "bgr": pillow_jxl.Decoder(channel_order="BGR"),
+        "big-endian": pillow_jxl.Decoder(endianness="big"),
+    }
+    encoder = pillow_jxl.Encoder(mode="RGB", lossless=True, effort=1)
+
+    result = {"image": args.image, "megapixels": args.megapixels, "timings": []}
+    for dtype in ("uint8", "uint16", "float32"):
+        arr = make_image(args.image, args.megapixels, dtype)
+        data = encoder.encode_array(arr)
+        print(f"\n{dtype} {arr.shape[1]}x{arr.shape[0]}")
+        for dec_name, dec in decoders.items():
+            for case_name, case in cases.items():
+                d = bench(lambda: case(dec, data), args.repeat)
+                print(f"  {dec_name:<16} {case_name:<13} {d:.3f}s")
+                result["timings"].append(
+                    {
+                        "dtype": dtype,
+                        "decoder": dec_name,
+                        "method": case_name,
+                        "duration": d,
+                    }
+                )
+
+    if args.output_json:
+        with open(args.output_json, "w") as f:
+            json.dump(result, f, indent=4)
+
+
+if __name__ == "__main__":
+    parser = ArgumentParser()
+    parser.add_argument(
+        "-i", "--image", help="Image file for decode benchmark", required=True
+    )
+    parser.add_argument(
+        "-m", "--megapixels", type=float, default=50, help="Approximate image size"
+    )
+    parser.add_argument("-r", "--repeat", type=int, default=3, help="Runs per case")
+    parser.add_argument("-o", "--output-json", help="Output JSON file")
+    args = parser.parse_args()
+    main(args)
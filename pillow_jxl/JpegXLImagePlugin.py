@@ -1,16 +1,48 @@
+import logging
+import os
 import warnings
+from functools import lru_cache
 from io import BytesIO
 
 import PIL
 from packaging.version import parse
 from PIL import Image, ImageFile
 
-from pillow_jxl import Decoder, Encoder
+from pillow_jxl import Decoder, Encoder, JxlWarning
+
+logger = logging.getLogger(__name__)
 
 _VALID_JXL_MODES = {"RGB", "RGBA", "L", "LA", "I;16", "F"}
 DECODE_THREADS = -1  # -1 detect available cpu cores, 0 disables parallelism
 
 
+@lru_cache(maxsize=4)
+def _get_decoder(num_threads):
+    # decoders keep their thread pool alive, so share them between images
+    return Decoder(num_threads=num_threads)
+
+
+def _is_tiff(data):
+    if data[:4] == b"II*\x00":
+        byteorder = "little"
+    elif data[:4] == b"MM\x00*":
+        byteorder = "big"
+    else:
+        return False
+    # the first IFD, with its 2-byte entry count, must be within the data
+    ifd_offset = int.from_bytes(data[4:8], byteorder)
+    return len(data) >= 8 and 8 <= ifd_offset <= len(data) - 2
+
+
+def _is_valid_exif(exif):
+    # the forms the encoder accepts: bare TIFF data, optionally after the JPEG
+    # Exif marker, or already framed Exif box contents
+    if _is_tiff(exif.removeprefix(b"Exif\x00\x00")):
+        return True
+    offset = int.from_bytes(exif[:4], "big")
+    return len(exif) >= 4 and _is_tiff(exif[4 + offset :])
+
+
 def _accept(data):
     return (
         data[:2] == b"\xff\x0a"
@@ -26,11 +58,22 @@ class JXLImageFile(ImageFile.ImageFile):
     __frame = 0
 
     def _open(self):
-        self.fc = self.fp.read()
-        self._decoder = Decoder(num_threads=DECODE_THREADS)
+        self._decoder = _get_decoder(DECODE_THREADS)
 
-        (self.jpeg, self._jxlinfo, self._data, icc_profile, jxl_boxes) = self._decoder(
-            self.fc
+        if self._reads_own_file():
+            # let the decoder map the file instead of reading it into Python
+            result = self._decoder.decode_file(self.filename)
+        else:
+            self.fc = self.fp.read()
+            result = self._decoder(self.fc)
+        (self.jpeg, self._jxlinfo, self._data, icc_profile, _) = result
+        logger.debug(
+            "Opened %s: %dx%d %s%s",
+            self.filename or "stream",
+            self._jxlinfo.width,
+            self._jxlinfo.height,
+            self._jxlinfo.mode,
+            ", reconstructing JPEG" if self.jpeg else "",
         )
         if self._jxlinfo.mode == "F;16":
             warnings.warn(
@@ -49,17 +92,15 @@ class JXLImageFile(ImageFile.ImageFile):
         else:
             self._size = (self._jxlinfo.width, self._jxlinfo.height)
             self.rawmode = self._jxlinfo.mode
-            # Read the exif data from the file
-            for box in jxl_boxes:
-                if box.box_type == b"Exif":
-                    exif_data = box.data
-                    if len(exif_data) > 8 and exif_data[4:8] in (
-                        b"II\x2a\x00",
-                        b"MM\x00\x2a",
-                    ):
-                        exif_data = exif_data[4:]
-                    self.info["exif"] = exif_data
-                    break
+            # The decoder strips the Exif box framing down to the TIFF data
+            if self._jxlinfo.exif is not None:
+                self.info["exif"] = self._jxlinfo.exif
+
+        # XMP and JUMBF boxes aren't part of a reconstructed JPEG's own info
+        if self._jxlinfo.xmp:
+            self.info.setdefault("xmp", self._jxlinfo.xmp)
+        if self._jxlinfo.jumbf:
+            self.info.setdefault("jumb", self._jxlinfo.jumbf)
 
         if icc_profile:
             self.info["icc_profile"] = icc_profile
@@ -85,6 +126,20 @@ class JXLImageFile(ImageFile.ImageFile):
     #         )
     #     self.__frame = frame
 
+    def _reads_own_file(self):
+        # fp must be the named file itself, read from its start. Image.open only
+        # sets _exclusive_fp once _open returns, so also compare the files.
+        if not self.filename:
+            return False
+        try:
+            if self.fp.tell() != 0:
+                return False
+            if self._exclusive_fp:
+                return True
+            return os.path.samestat(os.fstat(self.fp.fileno()), os.stat(self.filename))
+        except (AttributeError, OSError, ValueError):
+            return False
+
     def load(self):
         if self.__loaded != self.__frame:
             if self._data is None:
@@ -128,6 +183,11 @@ def _save(im, fp, filename, save_all=False):
     jpeg_encode = info.get("lossless_jpeg", None)
     num_threads = info.get("num_threads", -1)
     compress_metadata = info.get("compress_metadata", False)
+    target_size = info.get("target_size", None)
+    target_score = info.get("target_score", None)
+    verify = info.get("verify", False)
+    level = info.get("level", None)
+    streaming = info.get("streaming", False)
 
     enc = Encoder(
         mode=im.mode,
@@ -138,10 +198,13 @@ def _save(im, fp, filename, save_all=False):
         use_container=use_container,
         use_original_profile=use_original_profile,
         num_threads=num_threads,
+        verify=verify,
+        level=level,
     )
     # FIXME (Isotr0py): im.filename maybe None if parse stream
     # TODO (Isotr0py): This part should be refactored in the near future
     if im.format == "JPEG" and im.filename and (jpeg_encode or jpeg_encode is None):
+        logger.debug("Recompressing %s with JPEG reconstruction", im.filename)
         if jpeg_encode is None:
             warnings.warn(
                 "Using JPEG reconstruction to create lossless JXL image from JPEG. "
@@ -150,22 +213,49 @@ def _save(im, fp, filename, save_all=False):
                 stacklevel=2,
             )
         with open(im.filename, "rb") as f:
-            data = enc(f.read(), im.width, im.height, jpeg_encode=True)
+            enc.encode_to(fp, f.read(), im.width, im.height, jpeg_encode=True)
     else:
         exif = info.get("exif")
         if exif is None:
             exif = im.getexif()
             exif = exif.tobytes() if exif else None
-        if exif and exif.startswith(b"Exif\x00\x00"):
-            exif = exif[6:]
+        if exif and not _is_valid_exif(exif):
+            # metadata carried over from another file shouldn't stop the save
+            warnings.warn(
+                "Dropping Exif data that has no valid TIFF header",
+                JxlWarning,
+                stacklevel=2,
+            )
+            exif = None
+        xmp = info.get("xmp", im.info.get("xmp"))
+        if isinstance(xmp, str):
+            xmp = xmp.encode()
         metadata = {
             "exif": exif or None,
-            "jumb": info.get("jumb") or None,
-            "xmp": info.get("xmp") or None,
+            "jumb": info.get("jumb", im.info.get("jumb")) or None,
+            "xmp": xmp or None,
             "compress": compress_metadata,
         }
-        data = enc(im.tobytes(), im.width, im.height, jpeg_encode=False, **metadata)
-    fp.write(data)
+        if target_size is not None or target_score is not None:
+            data, _, _ = enc.encode_target(
+                im.tobytes(),
+                im.width,
+                im.height,
+                target_size=target_size,
+                target_score=target_score,
+                **metadata,
+            )
+            fp.write(data)
+        else:
+            enc.encode_to(
+                fp,
+                im.tobytes(),
+                im.width,
+                im.height,
+                jpeg_encode=False,
+                streaming=streaming,
+                **metadata,
+            )
 
 
 Image.register_open(JXLImageFile.format, JXLImageFile, _accept)
@@ -1,8 +1,29 @@
 # ruff: noqa
-from .pillow_jxl import Decoder, Encoder
+import logging
+
+from .pillow_jxl import (
+    CodestreamPart,
+    CompareResult,
+    Decoder,
+    Encoder,
+    JxlBox,
+    JxlContainer,
+    JxlException,
+    JxlVerificationError,
+    JxlWarning,
+    PixelBuffer,
+    compare,
+    strip_metadata,
+    unwrap_container,
+    wrap_codestream,
+)
 
 from pillow_jxl import JpegXLImagePlugin
 
+# diagnostics go to the "pillow_jxl" logger, silent unless the application
+# configures logging
+logging.getLogger(__name__).addHandler(logging.NullHandler())
+
 
 __doc__ = pillow_jxl.__doc__
 if hasattr(pillow_jxl, "__all__"):
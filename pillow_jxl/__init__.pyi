@@ -1,3 +1,21 @@
+import os
+from collections.abc import Buffer
+from typing import IO, Callable, Iterable
+
+class JxlException(RuntimeError): ...
+
+class JxlVerificationError(JxlException):
+    """
+    Raised when an encoder created with `verify=True` produces an image that
+    does not decode back to its input.
+    """
+
+class JxlWarning(UserWarning):
+    """
+    Issued when malformed container boxes or metadata are skipped by a
+    non-strict `Decoder` or `JxlContainer`.
+    """
+
 class ImageInfo:
     """
     A class contains metadata of ddecoded image.
@@ -8,6 +26,128 @@ class ImageInfo:
     height: int
     num_channels: int
     has_alpha_channel: bool
+    exif: bytes | None
+    """TIFF data of the `Exif` box, without its offset field, if any."""
+    xmp: bytes | None
+    """Payload of the `xml ` (XMP) box, if any."""
+    jumbf: bytes | None
+    """Payload of the `jumb` (JUMBF, e.g. C2PA) box, if any."""
+    level: int
+    """Codestream level declared by the `jxll` box, 5 without one."""
+    exceeds_level5: bool
+    """Whether the image size, bit depth or ICC profile exceed the limits of level 5."""
+
+class JxlBox:
+    """
+    A box of a jpeg-xl container. Brotli-compressed (`brob`) boxes are
+    decompressed and reported with their inner type.
+    """
+
+    box_type: bytes
+    data: bytes
+    compressed: bool
+
+class CodestreamPart:
+    """
+    A `jxlc` box, or one of the `jxlp` boxes a codestream is split over.
+    `file_offset` is where its codestream bytes start in `JxlContainer.tobytes()`,
+    which keeps the box headers as read, so also in the file of an unedited
+    container. `codestream_offset` is where they start in the reassembled
+    codestream.
+    """
+
+    index: int
+    is_last: bool
+    box_index: int
+    file_offset: int
+    codestream_offset: int
+    size: int
+
+class JxlContainer:
+    """
+    The boxes of a jpeg-xl container, which can be edited and written back
+    out without re-encoding the codestream. Box types are given as 4 bytes
+    or a 4 character string.
+    """
+
+    def __init__(self, data: bytes, strict: bool = True) -> None:
+        """
+        Parse the boxes of `data`. Malformed or truncated boxes raise ValueError
+        with their byte offset, or when `strict` is false, are skipped along with
+        the rest of the file with a `JxlWarning`.
+        """
+    @property
+    def boxes(self) -> list[JxlBox]: ...
+    def __len__(self) -> int: ...
+    def find(self, box_type: bytes | str) -> JxlBox | None: ...
+    def find_all(self, box_type: bytes | str) -> list[JxlBox]: ...
+    def add(self, box_type: bytes | str, data: bytes, index: int | None = None) -> None:
+        """
+        Insert a box at `index`, by default in front of the codestream.
+        """
+    def replace(self, box_type: bytes | str, data: bytes) -> None:
+        """
+        Replace the first box of `box_type`. Raises KeyError if there is none.
+        """
+    def remove(self, box_type: bytes | str) -> int:
+        """
+        Remove every box of `box_type`, returning how many were removed.
+        """
+    @property
+    def codestream(self) -> bytes:
+        """
+        The codestream, reassembled from `jxlp` parts if it is split. Raises
+        ValueError if the parts are out of order or the last one is not flagged.
+        """
+    @property
+    def codestream_parts(self) -> list[CodestreamPart]: ...
+    def tobytes(self) -> bytes: ...
+
+def strip_metadata(
+    data: bytes,
+    box_types: list[bytes | str] | None = None,
+    keep_container: bool = False,
+) -> bytes:
+    """
+    Remove metadata boxes from a jpeg-xl file without re-encoding it.
+
+    Args:
+        data(`bytes`): jpeg-xl container or bare codestream
+        box_types(`list[bytes | str]`, optional): box types to remove, Brotli-compressed
+            boxes included. Defaults to Exif, XMP and JUMBF. JPEG reconstruction
+            data is dropped as well when Exif or XMP boxes are removed.
+        keep_container(`bool`): keep the container even when only the codestream is left,
+            instead of returning a bare codestream
+
+    Returns:
+        `bytes`: the stripped file. Bare codestreams are returned unchanged.
+    """
+
+def wrap_codestream(
+    data: bytes,
+    level: int | None = None,
+    exif: bytes | None = None,
+    xmp: bytes | None = None,
+    jumb: bytes | None = None,
+) -> bytes:
+    """
+    Wrap a bare jpeg-xl codestream into a container without re-encoding it.
+
+    Args:
+        data(`bytes`): bare codestream
+        level(`int`, optional): codestream level, 5 or 10, recorded in a `jxll` box
+        exif(`bytes`): Exif data, as accepted by `Encoder`
+        xmp(`bytes`): XMP data
+        jumb(`bytes`): JUMBF data
+    """
+
+def unwrap_container(data: bytes) -> bytes:
+    """
+    Extract the bare codestream of a jpeg-xl container, concatenating `jxlp` parts.
+    All other boxes, metadata included, are dropped. Bare codestreams are returned
+    unchanged. Raises `ValueError` for containers declaring a level other than 5
+    in a `jxll` box, since a bare codestream is always taken to be level 5.
+    """
 
 class Encoder:
     def __init__(
@@ -17,19 +157,194 @@ class Encoder:
         lossless: bool = True,
         quality: float = 0.0,
         num_threads: int = -1,
+        verify: bool = False,
+        channel_order: str = "RGB",
+        endianness: str = "native",
+        level: int | None = None,
     ): ...
+    """
+        Args:
+            channel_order(`str`): order of the channels in input pixels,
+                `"RGB"`, `"BGR"` (BGR/BGRA, as used by OpenCV) or `"ARGB"`
+            endianness(`str`): byte order of 16 and 32-bit input samples,
+                `"native"`, `"little"` or `"big"`. Arrays passed to
+                `encode_array` always use their own byte order.
+            level(`int`, optional): codestream level, 5 or 10. Level 5 is
+                supported by every decoder; images beyond its limits raise
+                ValueError. Level 10 is declared in a `jxll` box, which puts
+                the image in a container. By default the lowest level the
+                image fits is picked.
+    """
     def __call__(
-        self, data: bytes, width: int, height: int, jpeg_encode: bool
+        self,
+        data: bytes,
+        width: int,
+        height: int,
+        jpeg_encode: bool,
+        exif: bytes | None = None,
+        jumb: bytes | None = None,
+        xmp: bytes | None = None,
+        compress: bool = False,
+        stride: int | None = None,
     ) -> bytes: ...
     """
         Encode a jpeg-xl image.
 
         Args:
             data(`bytes`): raw image bytes
+            exif(`bytes`): Exif TIFF data, optionally after an `Exif\\0\\0`
+                marker, or complete `Exif` box contents; the box offset field
+                is added when missing. Invalid TIFF headers raise ValueError.
+            stride(`int`): bytes from the start of one row to the next, for
+                padded rows; the last row may be unpadded. Defaults to
+                tightly packed rows.
+
+        Return:
+            `bytes`: The encoded jpeg-xl image.
+    """
+    def encode_many(
+        self,
+        images: list[tuple[bytes, int, int]],
+        jpeg_encode: bool = False,
+        max_workers: int | None = None,
+        exif: bytes | None = None,
+        jumb: bytes | None = None,
+        xmp: bytes | None = None,
+        compress: bool = False,
+        stride: int | None = None,
+    ) -> list[bytes | Exception]: ...
+    """
+        Encode many jpeg-xl images concurrently, releasing the GIL for the
+        whole batch.
+
+        Args:
+            images: `(data, width, height)` for each image
+            max_workers(`int`): number of worker threads, defaults to the CPU count
+            exif, jumb, xmp, compress, stride: as for `__call__`, applied to
+                every image
+
+        Return:
+            The encoded image for each input, in order, or the exception
+            raised while encoding it.
+    """
+    def encode_array(
+        self,
+        array: Buffer,
+        exif: bytes | None = None,
+        jumb: bytes | None = None,
+        xmp: bytes | None = None,
+        compress: bool = False,
+    ) -> bytes: ...
+    """
+        Encode a numpy array, or any other buffer, without repacking it in
+        Python. The encoder's mode is ignored in favour of the array's own
+        layout.
+
+        Args:
+            array: `uint8`, `uint16`, `float16` or `float32` samples shaped
+                `(height, width)` or `(height, width, channels)`; 2 and 4
+                channels include alpha. Integer samples are sRGB and floats
+                linear. Non-contiguous views are supported.
+
+        Return:
+            `bytes`: The encoded jpeg-xl image.
+    """
+    def encode_target(
+        self,
+        data: bytes,
+        width: int,
+        height: int,
+        target_size: int | None = None,
+        target_score: float | None = None,
+        max_iterations: int = 8,
+        exif: bytes | None = None,
+        jumb: bytes | None = None,
+        xmp: bytes | None = None,
+        compress: bool = False,
+    ) -> tuple[bytes, int, float]: ...
+    """
+        Encode a jpeg-xl image, searching the distance that meets a target.
+
+        Args:
+            data(`bytes`): raw image bytes
+            target_size(`int`): maximum encoded size in bytes
+            target_score(`float`): minimum SSIMULACRA2 score
+            max_iterations(`int`): number of bisection steps
 
         Return:
             `bytes`: The encoded jpeg-xl image.
+            `int`: The encoded size in bytes.
+            `float`: The butteraugli distance used.
+    """
+    def encode_to(
+        self,
+        fp: IO[bytes] | str | os.PathLike,
+        data: bytes,
+        width: int,
+        height: int,
+        jpeg_encode: bool,
+        exif: bytes | None = None,
+        jumb: bytes | None = None,
+        xmp: bytes | None = None,
+        compress: bool = False,
+        stride: int | None = None,
+        streaming: bool = False,
+    ) -> int: ...
+    """
+        Encode a jpeg-xl image, writing the output to a file instead of
+        returning it.
+
+        Args:
+            fp: writable binary file object, or a path to create
+            data(`bytes`): raw image bytes
+            streaming(`bool`): encode with the chunked encoder, which hands the
+                pixels to libjxl a tile at a time and writes the output as it is
+                produced. Ignored for JPEG reconstruction and `verify=True`.
+
+        Return:
+            `int`: The number of bytes written.
+    """
+    def encode_stream(
+        self,
+        source: Callable[[int, int, int, int], bytes] | Iterable[bytes],
+        fp: IO[bytes],
+        width: int,
+        height: int,
+        exif: bytes | None = None,
+        jumb: bytes | None = None,
+        xmp: bytes | None = None,
+        compress: bool = False,
+    ) -> int: ...
     """
+        Encode a jpeg-xl image without holding the whole image in memory.
+
+        Args:
+            source: either a callable `source(x, y, width, height)` returning
+                the packed pixels of that tile, or an iterable yielding whole
+                rows top to bottom (any number of rows per item)
+            fp: writable binary file object receiving the output as it is produced
+
+        Return:
+            `int`: The number of bytes written to `fp`.
+
+        If `source` raises, it is not called again and the error is raised once
+        libjxl stops; nothing is written to `fp` after the failure, but output
+        produced before it is not taken back.
+    """
+
+class PixelBuffer:
+    """
+    Read-only decoded pixels supporting the buffer protocol, so that
+    `numpy.asarray` and `Image.frombuffer` can use them without copying.
+    Images are shaped `(height, width)` or `(height, width, channels)`,
+    reconstructed jpeg data is one-dimensional.
+    """
+
+    shape: list[int]
+    format: str
+    def __len__(self) -> int: ...
+    def __buffer__(self, flags: int) -> memoryview: ...
+    def tobytes(self) -> bytes: ...
 
 class Decoder:
     """
@@ -37,10 +352,31 @@ class Decoder:
 
     Args:
         parallel(`bool`): enable parallel decoding
+        channel_order(`str`): order of the channels in decoded pixels,
+            `"RGB"`, `"BGR"` (BGR/BGRA, as used by OpenCV) or `"ARGB"`
+        endianness(`str`): byte order of 16 and 32-bit decoded samples,
+            `"native"`, `"little"` or `"big"`
+        dither(`str`): dithering used when color images are reduced to 8
+            bits for Pillow, `"none"`, `"ordered"` or `"error-diffusion"`.
+            Samples are rounded to the nearest value either way.
+        clamp(`bool`): saturate floating point samples outside [0, 1] when
+            reducing them to 8 bits; if false, such samples raise ValueError
+        strict(`bool`): raise ValueError, with the byte offset, for malformed,
+            truncated or misordered container boxes and invalid Exif boxes,
+            and RuntimeError for Brotli-compressed boxes that fail to
+            decompress, instead of skipping them with a `JxlWarning`
     """
 
-    def __init__(self, num_threads: int = -1): ...
-    def __call__(self, data: bytes) -> (bool, ImageInfo, bytes): ...
+    def __init__(
+        self,
+        num_threads: int = -1,
+        channel_order: str = "RGB",
+        endianness: str = "native",
+        dither: str = "none",
+        clamp: bool = True,
+        strict: bool = False,
+    ): ...
+    def __call__(self, data: bytes) -> (bool, ImageInfo, PixelBuffer): ...
     """
         Decode a jpeg-xl image.
 
@@ -50,5 +386,100 @@ class Decoder:
         Return:
             `bool`: If the jpeg is reconstructed
             `ImageInfo`: The metadata of decoded image
-            `bytes`: The decoded image.
+            `PixelBuffer`: The decoded pixels, or the reconstructed jpeg.
+    """
+    def decode_array(self, data: bytes) -> PixelBuffer: ...
+    """
+        Decode a jpeg-xl image to its native sample type (`uint8`, `uint16`,
+        `float16` or `float32`) with every channel kept, ready for
+        `numpy.asarray`.
+
+        Args:
+            data(`bytes`): jpeg-xl image
+
+        Return:
+            `PixelBuffer`: Samples shaped `(height, width)` or
+            `(height, width, channels)`.
+    """
+    def decode_many(
+        self, data: list[bytes], max_workers: int | None = None
+    ) -> list[tuple[bool, ImageInfo, PixelBuffer, bytes, list] | Exception]: ...
+    """
+        Decode many jpeg-xl images concurrently, releasing the GIL for the
+        whole batch.
+
+        Args:
+            data: jpeg-xl images
+            max_workers(`int`): number of worker threads, defaults to the CPU count
+
+        Return:
+            The result `__call__` would return for each input, in order, or
+            the exception raised while decoding it.
+    """
+    def decode_into(self, data: bytes, out: Buffer) -> ImageInfo: ...
+    """
+        Decode a jpeg-xl image into a preallocated writable buffer, such as a
+        bytearray or numpy array, without allocating new pixel storage. The
+        buffer must be C-contiguous and exactly as large as the decoded image
+        in the Pillow-compatible layout `__call__` returns; typed buffers
+        must match the sample type. JPEG reconstruction is not performed.
+        Color images above 8 bits are decoded to a scratch buffer kept on the
+        decoder first, to reduce them with the same `dither` and `clamp` as
+        `__call__`.
+
+        Args:
+            data(`bytes`): jpeg-xl image
+            out: writable buffer receiving the pixels
+
+        Return:
+            `ImageInfo`: The metadata of decoded image
+    """
+    def decode_file(
+        self, path: str | os.PathLike
+    ) -> (bool, ImageInfo, PixelBuffer, bytes, list): ...
     """
+        Decode a jpeg-xl file, memory-mapping it instead of reading it into
+        Python first.
+
+        Args:
+            path: path of the jpeg-xl file
+
+        Return:
+            Same as `__call__`.
+    """
+
+class CompareResult:
+    """
+    Perceptual distance between two images.
+    """
+
+    ssimulacra2: float
+
+def compare(
+    reference: bytes,
+    distorted: bytes,
+    mode: str | None = None,
+    width: int | None = None,
+    height: int | None = None,
+    num_threads: int = -1,
+) -> CompareResult: ...
+
+"""
+    Compare a distorted image against a reference with SSIMULACRA2, following
+    libjxl's `ssimulacra2` tool.
+
+    JPEG XL images are compared at their full sample precision. Images with an
+    ICC profile or a color space other than sRGB or linear sRGB are rejected,
+    unless they were encoded lossily and libjxl can convert them. Raw pixels
+    are taken as sRGB, or linear sRGB for `I;16` and `F`.
+
+    Args:
+        reference(`bytes`): jpeg-xl image or raw image bytes
+        distorted(`bytes`): jpeg-xl image or raw image bytes
+        mode(`str`): mode of raw image bytes
+        width(`int`): width of raw image bytes
+        height(`int`): height of raw image bytes
+
+    Return:
+        `CompareResult`: SSIMULACRA2 score
+"""
@@ -0,0 +1,104 @@
+//! Images passed as buffer protocol objects, such as numpy arrays.
+
+use std::borrow::Cow;
+use std::slice;
+
+use pyo3::buffer::PyUntypedBuffer;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+use crate::encode::{PixelType, SampleType};
+
+/// A borrowed `(height, width)` or `(height, width, channels)` array.
+///
+/// One and three channel arrays are grayscale and RGB, two and four channel
+/// arrays additionally carry alpha. Integer samples are sRGB encoded and
+/// floating point samples are linear, like the `L`/`RGB` and `F` modes.
+pub(crate) struct ArrayImage {
+    buffer: PyUntypedBuffer,
+    pub(crate) pixel_type: PixelType,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+}
+
+impl ArrayImage {
+    pub(crate) fn from_buffer(obj: &Bound<'_, PyAny>) -> PyResult<Self> {
+        let buffer = PyUntypedBuffer::get(obj)?;
+        let format = buffer.format().to_string_lossy();
+        let sample_type = SampleType::from_format(&format).ok_or_else(|| {
+            PyValueError::new_err(format!(
+                "Unsupported array format {format:?}, expected uint8, uint16, float16 or float32"
+            ))
+        })?;
+        let (height, width, num_channels) = match *buffer.shape() {
+            [height, width] => (height, width, 1),
+            [height, width, channels] => (height, width, channels),
+            _ => {
+                return Err(PyValueError::new_err(
+                    "Arrays must be shaped (height, width) or (height, width, channels)",
+                ))
+            }
+        };
+        if !(1..=4).contains(&num_channels) {
+            return Err(PyValueError::new_err(format!(
+                "Arrays must have 1 to 4 channels, got {num_channels}"
+            )));
+        }
+        if buffer.suboffsets().is_some() {
+            return Err(PyValueError::new_err("Indirect buffers are not supported"));
+        }
+        let dimension = |size: usize| {
+            u32::try_from(size).map_err(|_| PyValueError::new_err("Array is too large"))
+        };
+        Ok(Self {
+            pixel_type: PixelType {
+                sample_type,
+                num_channels: num_channels as u32,
+                has_alpha: num_channels % 2 == 0,
+                linear: matches!(sample_type, SampleType::Float16 | SampleType::Float32),
+            },
+            width: dimension(width)?,
+            height: dimension(height)?,
+            buffer,
+        })
+    }
+
+    /// The samples in row-major order and the row stride in bytes, if rows
+    /// are padded. Arrays whose rows are each contiguous, like crops of a
+    /// larger array, are borrowed; anything else is packed into a copy.
+    pub(crate) fn pixels(&self) -> (Cow<'_, [u8]>, Option<usize>) {
+        let base = self.buffer.buf_ptr() as *const u8;
+        if self.buffer.is_c_contiguous() {
+            let data = unsafe { slice::from_raw_parts(base, self.buffer.len_bytes()) };
+            return (Cow::Borrowed(data), None);
+        }
+        let item_size = self.buffer.item_size();
+        let num_channels = self.pixel_type.num_channels as usize;
+        let strides = self.buffer.strides();
+        let (row_stride, pixel_stride) = (strides[0], strides[1]);
+        let sample_stride = strides.get(2).copied().unwrap_or(item_size as isize);
+
+        let row_bytes = self.width as usize * num_channels * item_size;
+        let packed_rows = sample_stride == item_size as isize
+            && (self.width == 1 || pixel_stride == (num_channels * item_size) as isize);
+        if packed_rows && row_stride >= row_bytes as isize && self.height > 0 {
+            let row_stride = row_stride as usize;
+            let len = (self.height as usize - 1) * row_stride + row_bytes;
+            let data = unsafe { slice::from_raw_parts(base, len) };
+            return (Cow::Borrowed(data), Some(row_stride));
+        }
+
+        let mut packed = Vec::with_capacity(self.buffer.len_bytes());
+        for y in 0..self.height as isize {
+            for x in 0..self.width as isize {
+                for c in 0..num_channels as isize {
+                    let offset = y * row_stride + x * pixel_stride + c * sample_stride;
+                    packed.extend_from_slice(unsafe {
+                        slice::from_raw_parts(base.offset(offset), item_size)
+                    });
+                }
+            }
+        }
+        (Cow::Owned(packed), None)
+    }
+}
@@ -0,0 +1,97 @@
+//! Processing many images at once on a bounded pool of worker threads.
+
+use std::num::NonZeroUsize;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::thread;
+
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::IntoPyObjectExt;
+
+use jpegxl_rs::parallel::threads_runner::ThreadsRunner;
+
+/// Number of workers to use for `len` items when the caller asked for `max_workers`
+fn worker_count(len: usize, max_workers: Option<usize>) -> PyResult<usize> {
+    let workers = match max_workers {
+        Some(0) => return Err(PyValueError::new_err("max_workers must be at least 1")),
+        Some(n) => n,
+        None => thread::available_parallelism().map_or(1, NonZeroUsize::get),
+    };
+    Ok(workers.min(len).max(1))
+}
+
+fn panicked() -> PyErr {
+    PyRuntimeError::new_err("Batch worker panicked")
+}
+
+/// Run `f` over `items` on up to `max_workers` threads, keeping results in order.
+///
+/// Each worker owns a single-threaded runner, as parallelism comes from
+/// processing several images at once. Errors are collected per item, and a
+/// panic fails only the items it interrupted.
+pub(crate) fn run<T, R, F>(
+    items: &[T],
+    max_workers: Option<usize>,
+    f: F,
+) -> PyResult<Vec<PyResult<R>>>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&ThreadsRunner, &T) -> PyResult<R> + Sync,
+{
+    let workers = worker_count(items.len(), max_workers)?;
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<PyResult<R>>>> = Mutex::new(items.iter().map(|_| None).collect());
+
+    thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers)
+            .map(|_| {
+                scope.spawn(|| -> PyResult<()> {
+                    let runner = ThreadsRunner::new(None, Some(0)).ok_or_else(|| {
+                        PyRuntimeError::new_err("Could not create JxlThreadsRunner")
+                    })?;
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(index) else {
+                            return Ok(());
+                        };
+                        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&runner, item)))
+                            .unwrap_or_else(|_| Err(panicked()));
+                        results.lock().unwrap_or_else(PoisonError::into_inner)[index] =
+                            Some(result);
+                    }
+                })
+            })
+            .collect();
+        // Items a failed worker didn't get to are reported below
+        handles
+            .into_iter()
+            .try_for_each(|handle| handle.join().unwrap_or(Ok(())))
+    })?;
+
+    Ok(results
+        .into_inner()
+        .unwrap_or_else(PoisonError::into_inner)
+        .into_iter()
+        .map(|result| result.unwrap_or_else(|| Err(panicked())))
+        .collect())
+}
+
+/// Convert per-item results into a Python list, with exceptions in place of failures
+pub(crate) fn into_pylist<'py, R>(
+    py: Python<'py>,
+    results: Vec<PyResult<R>>,
+) -> PyResult<Vec<Bound<'py, PyAny>>>
+where
+    R: IntoPyObject<'py>,
+{
+    results
+        .into_iter()
+        .map(|result| match result {
+            Ok(value) => value.into_bound_py_any(py),
+            Err(e) => Ok(e.into_value(py).into_bound(py).into_any()),
+        })
+        .collect()
+}
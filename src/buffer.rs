@@ -0,0 +1,233 @@
+use std::ffi::{c_int, c_void, CStr};
+use std::ptr::null_mut;
+
+use half::f16;
+use pyo3::exceptions::PyBufferError;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use pyo3::{ffi, IntoPyObjectExt};
+
+use crate::format::Endianness;
+
+/// Stride of the flat byte view given to consumers that don't ask for a format
+static BYTE_STRIDE: isize = 1;
+
+/// Decoded samples in their native type
+pub(crate) enum Samples {
+    Uint8(Vec<u8>),
+    Uint16(Vec<u16>),
+    Float16(Vec<f16>),
+    Float32(Vec<f32>),
+}
+
+impl Samples {
+    fn as_bytes(&self) -> &[u8] {
+        match self {
+            Samples::Uint8(samples) => samples,
+            Samples::Uint16(samples) => bytemuck::cast_slice(samples),
+            Samples::Float16(samples) => bytemuck::cast_slice(samples),
+            Samples::Float32(samples) => bytemuck::cast_slice(samples),
+        }
+    }
+
+    pub(crate) fn as_bytes_mut(&mut self) -> &mut [u8] {
+        match self {
+            Samples::Uint8(samples) => samples,
+            Samples::Uint16(samples) => bytemuck::cast_slice_mut(samples),
+            Samples::Float16(samples) => bytemuck::cast_slice_mut(samples),
+            Samples::Float32(samples) => bytemuck::cast_slice_mut(samples),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Samples::Uint8(samples) => samples.len(),
+            Samples::Uint16(samples) => samples.len(),
+            Samples::Float16(samples) => samples.len(),
+            Samples::Float32(samples) => samples.len(),
+        }
+    }
+
+    pub(crate) fn item_size(&self) -> usize {
+        self.format(Endianness::Native).1
+    }
+
+    /// struct module format string and item size for samples stored in `endianness`
+    fn format(&self, endianness: Endianness) -> (&'static CStr, usize) {
+        let (native, little, big, size) = match self {
+            Samples::Uint8(_) => return (c"B", 1),
+            Samples::Uint16(_) => (c"H", c"<H", c">H", 2),
+            Samples::Float16(_) => (c"e", c"<e", c">e", 2),
+            Samples::Float32(_) => (c"f", c"<f", c">f", 4),
+        };
+        let format = match endianness {
+            Endianness::Native => native,
+            Endianness::Little => little,
+            Endianness::Big => big,
+        };
+        (format, size)
+    }
+}
+
+/// Read-only pixel data exposed through the buffer protocol.
+///
+/// Images are shaped `(height, width)` or `(height, width, channels)`;
+/// reconstructed JPEG bytes are one-dimensional.
+#[pyclass(module = "pillow_jxl", frozen)]
+pub struct PixelBuffer {
+    samples: Samples,
+    endianness: Endianness,
+    shape: Vec<isize>,
+    strides: Vec<isize>,
+    /// Shape of the flat byte view
+    byte_len: isize,
+}
+
+impl PixelBuffer {
+    pub(crate) fn from_bytes(data: Vec<u8>) -> Self {
+        Self::with_shape(Samples::Uint8(data), vec![])
+    }
+
+    pub(crate) fn image(samples: Samples, width: u32, height: u32) -> Self {
+        let pixels = (width as usize * height as usize).max(1);
+        let channels = samples.len() / pixels;
+        let mut shape = vec![height as isize, width as isize];
+        if channels != 1 {
+            shape.push(channels as isize);
+        }
+        Self::with_shape(samples, shape)
+    }
+
+    /// Mark the samples as stored in `endianness` rather than native byte order
+    pub(crate) fn with_endianness(mut self, endianness: Endianness) -> Self {
+        self.endianness = endianness;
+        self
+    }
+
+    fn with_shape(samples: Samples, mut shape: Vec<isize>) -> Self {
+        if shape.is_empty() {
+            shape.push(samples.len() as isize);
+        }
+        // C-contiguous strides
+        let mut strides = vec![samples.item_size() as isize; shape.len()];
+        for i in (0..shape.len() - 1).rev() {
+            strides[i] = strides[i + 1] * shape[i + 1];
+        }
+        Self {
+            byte_len: samples.as_bytes().len() as isize,
+            samples,
+            endianness: Endianness::Native,
+            shape,
+            strides,
+        }
+    }
+}
+
+#[pymethods]
+impl PixelBuffer {
+    unsafe fn __getbuffer__(
+        slf: Bound<'_, Self>,
+        view: *mut ffi::Py_buffer,
+        flags: c_int,
+    ) -> PyResult<()> {
+        if view.is_null() {
+            return Err(PyBufferError::new_err("View is null"));
+        }
+        if (flags & ffi::PyBUF_WRITABLE) == ffi::PyBUF_WRITABLE {
+            return Err(PyBufferError::new_err("PixelBuffer is read-only"));
+        }
+        let this = slf.get();
+        let bytes = this.samples.as_bytes();
+        let (format, itemsize) = this.samples.format(this.endianness);
+        let has_format = (flags & ffi::PyBUF_FORMAT) == ffi::PyBUF_FORMAT;
+        // Without a format, consumers take items to be unsigned bytes, so
+        // wider samples are given as a flat buffer of bytes
+        let (itemsize, shape, strides) = if has_format || itemsize == 1 {
+            (itemsize, &this.shape[..], &this.strides[..])
+        } else {
+            (
+                1,
+                std::slice::from_ref(&this.byte_len),
+                std::slice::from_ref(&BYTE_STRIDE),
+            )
+        };
+
+        // The view holds a reference to `slf`, which keeps the samples alive
+        // and, since the class is frozen, unchanged.
+        unsafe {
+            (*view).obj = slf.clone().into_any().into_ptr();
+            (*view).buf = bytes.as_ptr() as *mut c_void;
+            (*view).len = bytes.len() as isize;
+            (*view).readonly = 1;
+            (*view).itemsize = itemsize as isize;
+            (*view).format = if has_format {
+                format.as_ptr() as *mut _
+            } else {
+                null_mut()
+            };
+            // Without a shape, consumers see a flat buffer of items
+            (*view).ndim = if (flags & ffi::PyBUF_ND) == ffi::PyBUF_ND {
+                shape.len() as c_int
+            } else {
+                1
+            };
+            (*view).shape = if (flags & ffi::PyBUF_ND) == ffi::PyBUF_ND {
+                shape.as_ptr() as *mut _
+            } else {
+                null_mut()
+            };
+            (*view).strides = if (flags & ffi::PyBUF_STRIDES) == ffi::PyBUF_STRIDES {
+                strides.as_ptr() as *mut _
+            } else {
+                null_mut()
+            };
+            (*view).suboffsets = null_mut();
+            (*view).internal = null_mut();
+        }
+        Ok(())
+    }
+
+    unsafe fn __releasebuffer__(&self, _view: *mut ffi::Py_buffer) {}
+
+    fn __len__(&self) -> usize {
+        self.samples.as_bytes().len()
+    }
+
+    fn __eq__(&self, py: Python, other: &Bound<'_, PyAny>) -> PyResult<Py<PyAny>> {
+        match other.extract::<&[u8]>() {
+            Ok(other) => (self.samples.as_bytes() == other).into_py_any(py),
+            Err(_) => match other.cast::<PixelBuffer>() {
+                Ok(other) => {
+                    (self.samples.as_bytes() == other.get().samples.as_bytes()).into_py_any(py)
+                }
+                Err(_) => Ok(py.NotImplemented()),
+            },
+        }
+    }
+
+    #[getter]
+    fn shape(&self) -> Vec<isize> {
+        self.shape.clone()
+    }
+
+    #[getter]
+    fn format(&self) -> String {
+        self.samples
+            .format(self.endianness)
+            .0
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn tobytes<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, self.samples.as_bytes())
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "PixelBuffer(shape={:?}, format={:?})",
+            self.shape,
+            self.format()
+        )
+    }
+}
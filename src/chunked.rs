@@ -0,0 +1,208 @@
+//! Splitting per-sample conversions of large images across threads.
+//!
+//! The work runs on the libjxl thread pool of the `Encoder` or `Decoder`
+//! doing the conversion, so it follows its `num_threads` and doesn't start
+//! threads of its own.
+
+use std::any::Any;
+use std::cell::Cell;
+use std::ffi::c_void;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::prelude::*;
+
+use jpegxl_rs::parallel::threads_runner::ThreadsRunner;
+use jpegxl_rs::parallel::ParallelRunner;
+use jpegxl_sys::threads::parallel_runner::{
+    JxlParallelRetCode, JxlParallelRunner, JXL_PARALLEL_RET_SUCCESS,
+};
+
+/// Below this many samples per task, splitting costs more than it saves
+const MIN_SAMPLES_PER_THREAD: usize = 1 << 20;
+
+#[derive(Clone, Copy)]
+struct Runner {
+    run: JxlParallelRunner,
+    opaque: *mut c_void,
+    /// Number of tasks the runner works on at once
+    threads: usize,
+}
+
+thread_local! {
+    static RUNNER: Cell<Option<Runner>> = const { Cell::new(None) };
+}
+
+/// Restores the previous runner, also when the conversion panics
+struct RunnerGuard(Option<Runner>);
+
+impl Drop for RunnerGuard {
+    fn drop(&mut self) {
+        RUNNER.set(self.0);
+    }
+}
+
+/// Run `f` with conversions on this thread split across the threads of
+/// `runner`, which must not be running a libjxl job meanwhile.
+///
+/// Conversions outside of this, such as those in libjxl callbacks, stay on
+/// the calling thread.
+pub(crate) fn with_runner<R>(runner: &ThreadsRunner, f: impl FnOnce() -> R) -> R {
+    let run = runner.runner();
+    let opaque = runner.as_opaque_ptr();
+    let _guard = RunnerGuard(RUNNER.replace(Some(Runner {
+        run,
+        opaque,
+        threads: thread_count(run, opaque),
+    })));
+    f()
+}
+
+unsafe extern "C-unwind" fn count_threads(
+    opaque: *mut c_void,
+    num_threads: usize,
+) -> JxlParallelRetCode {
+    unsafe { *opaque.cast::<usize>() = num_threads };
+    // Stop before any task is handed out
+    -1
+}
+
+unsafe extern "C-unwind" fn no_task(_opaque: *mut c_void, _value: u32, _thread_id: usize) {}
+
+/// Ask `run` how many threads it has. libjxl passes the count to the init
+/// callback of a job before anything else, also when the runner is busy.
+fn thread_count(run: JxlParallelRunner, opaque: *mut c_void) -> usize {
+    let mut threads = 1;
+    unsafe {
+        run(
+            opaque,
+            (&raw mut threads).cast(),
+            count_threads,
+            no_task,
+            0,
+            1,
+        );
+    }
+    threads
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The current runner and the number of rows of `row_len` samples given to
+/// each of its tasks, or `None` when `rows` aren't worth splitting.
+fn split(rows: usize, row_len: usize) -> Option<(Runner, usize)> {
+    let runner = RUNNER.get()?;
+    let tasks = runner
+        .threads
+        .min((rows * row_len) / MIN_SAMPLES_PER_THREAD);
+    if tasks <= 1 {
+        return None;
+    }
+    Some((runner, rows.div_ceil(tasks)))
+}
+
+struct Job<'a> {
+    f: &'a (dyn Fn(usize) + Sync),
+    started: AtomicBool,
+    panic: Mutex<Option<Box<dyn Any + Send>>>,
+}
+
+unsafe extern "C-unwind" fn init_job(
+    _opaque: *mut c_void,
+    _num_threads: usize,
+) -> JxlParallelRetCode {
+    JXL_PARALLEL_RET_SUCCESS
+}
+
+unsafe extern "C-unwind" fn run_task(opaque: *mut c_void, value: u32, _thread_id: usize) {
+    let job = unsafe { &*opaque.cast::<Job>() };
+    job.started.store(true, Ordering::Relaxed);
+    // Unwinding through libjxl's worker threads would abort the process
+    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| (job.f)(value as usize))) {
+        lock(&job.panic).get_or_insert(payload);
+    }
+}
+
+/// Call `f(i)` for every `i` in `0..tasks` on the threads of `runner`
+fn run(runner: Runner, tasks: usize, f: &(dyn Fn(usize) + Sync)) -> PyResult<()> {
+    let job = Job {
+        f,
+        started: AtomicBool::new(false),
+        panic: Mutex::new(None),
+    };
+    let status = unsafe {
+        (runner.run)(
+            runner.opaque,
+            (&raw const job).cast_mut().cast(),
+            init_job,
+            run_task,
+            0,
+            tasks as u32,
+        )
+    };
+    if let Some(payload) = job
+        .panic
+        .into_inner()
+        .unwrap_or_else(PoisonError::into_inner)
+    {
+        panic::resume_unwind(payload);
+    }
+    if status == JXL_PARALLEL_RET_SUCCESS {
+        return Ok(());
+    }
+    if job.started.into_inner() {
+        return Err(PyRuntimeError::new_err("libjxl thread runner failed"));
+    }
+    // libjxl refuses before running anything, e.g. when already busy
+    (0..tasks).for_each(f);
+    Ok(())
+}
+
+/// Apply `f(first_row, input, output)` to matching runs of whole rows of
+/// `input` and `output`, which hold the same number of `row_len` sample rows.
+pub(crate) fn zip_rows<T, U, F>(input: &[T], output: &mut [U], row_len: usize, f: F) -> PyResult<()>
+where
+    T: Sync,
+    U: Send,
+    F: Fn(usize, &[T], &mut [U]) + Sync,
+{
+    let row_len = row_len.max(1);
+    let rows = input.len().div_ceil(row_len);
+    let Some((runner, chunk_rows)) = split(rows, row_len) else {
+        f(0, input, output);
+        return Ok(());
+    };
+    let chunk_len = chunk_rows * row_len;
+    let chunks: Vec<_> = input
+        .chunks(chunk_len)
+        .zip(output.chunks_mut(chunk_len))
+        .map(Mutex::new)
+        .collect();
+    run(runner, chunks.len(), &|i| {
+        let (input, output) = &mut *lock(&chunks[i]);
+        f(i * chunk_rows, input, output);
+    })
+}
+
+/// Apply `f` to runs of whole rows of `data`, each `row_len` long, in place.
+pub(crate) fn for_rows<T, F>(data: &mut [T], row_len: usize, f: F) -> PyResult<()>
+where
+    T: Send,
+    F: Fn(&mut [T]) + Sync,
+{
+    let row_len = row_len.max(1);
+    let rows = data.len().div_ceil(row_len);
+    let Some((runner, chunk_rows)) = split(rows, row_len) else {
+        f(data);
+        return Ok(());
+    };
+    let chunks: Vec<_> = data
+        .chunks_mut(chunk_rows * row_len)
+        .map(Mutex::new)
+        .collect();
+    run(runner, chunks.len(), &|i| f(&mut lock(&chunks[i])))
+}
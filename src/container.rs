@@ -0,0 +1,564 @@
+//! Reading and rewriting the boxes of a JPEG XL container (ISO/IEC 18181-2).
+
+use std::borrow::Cow;
+
+use pyo3::exceptions::{PyKeyError, PyValueError};
+use pyo3::prelude::*;
+
+use crate::decode::{JxlBox, JXL_CODESTREAM_SIGNATURE, JXL_CONTAINER_SIGNATURE};
+use crate::exif;
+use crate::level;
+use crate::warn;
+
+/// Boxes that must open every container, in this order
+const SIGNATURE_BOXES: [&[u8; 4]; 2] = [b"JXL ", b"ftyp"];
+
+/// Boxes holding the codestream, which metadata is added in front of by default
+const CODESTREAM_BOXES: [&[u8; 4]; 2] = [b"jxlc", b"jxlp"];
+
+/// Contents of the signature and file type boxes opening every container
+const SIGNATURE_CONTENTS: &[u8] = b"\r\n\x87\n";
+const FTYP_CONTENTS: &[u8] = b"jxl \0\0\0\0jxl ";
+
+/// Flag marking the last `jxlp` box in its 4-byte sequence index
+const LAST_PART_FLAG: u32 = 1 << 31;
+
+/// Boxes removed by `strip_metadata` unless told otherwise
+const METADATA_BOXES: [&[u8; 4]; 3] = [b"Exif", b"xml ", b"jumb"];
+
+fn type_name(box_type: &[u8; 4]) -> Cow<'_, str> {
+    String::from_utf8_lossy(box_type)
+}
+
+/// Size of the header of `jxl_box`, which has a 64-bit size if it was read
+/// with one or its contents don't fit a 32-bit size
+fn header_size(jxl_box: &JxlBox) -> usize {
+    if jxl_box.large_header() || 8 + jxl_box.data().len() > u32::MAX as usize {
+        16
+    } else {
+        8
+    }
+}
+
+/// A box type given as 4 bytes or a 4 character string
+fn extract_box_type(box_type: &Bound<'_, PyAny>) -> PyResult<[u8; 4]> {
+    let bytes = match box_type.extract::<&[u8]>() {
+        Ok(bytes) => bytes.to_vec(),
+        Err(_) => box_type.extract::<String>()?.into_bytes(),
+    };
+    bytes
+        .try_into()
+        .map_err(|_| PyValueError::new_err("Box types must be exactly 4 bytes"))
+}
+
+/// Split `data` into `boxes`, stopping at the first malformed or truncated box
+fn parse_boxes(data: &[u8], boxes: &mut Vec<JxlBox>) -> PyResult<()> {
+    let mut pos = 0;
+    while pos < data.len() {
+        let Some(header) = data.get(pos..pos + 8) else {
+            return Err(PyValueError::new_err(format!(
+                "Truncated box header at byte {pos}"
+            )));
+        };
+        let box_size = u32::from_be_bytes(header[..4].try_into().unwrap());
+        let box_type: [u8; 4] = header[4..].try_into().unwrap();
+        let (header_length, box_length) = match box_size {
+            // 64-bit box size
+            1 => {
+                let Some(large_size) = data.get(pos + 8..pos + 16) else {
+                    return Err(PyValueError::new_err(format!(
+                        "Truncated large box header at byte {pos}"
+                    )));
+                };
+                let large_size = u64::from_be_bytes(large_size.try_into().unwrap());
+                match usize::try_from(large_size) {
+                    Ok(size) if size >= 16 => (16, size),
+                    _ => {
+                        return Err(PyValueError::new_err(format!(
+                            "Invalid large box size {large_size} at byte {pos}"
+                        )))
+                    }
+                }
+            }
+            // The box extends to the end of the file
+            0 => (8, data.len() - pos),
+            2..=7 => {
+                return Err(PyValueError::new_err(format!(
+                    "Invalid box size {box_size} at byte {pos}"
+                )))
+            }
+            size => (8, size as usize),
+        };
+        let end = pos
+            .checked_add(box_length)
+            .filter(|&end| end <= data.len())
+            .ok_or_else(|| {
+                PyValueError::new_err(format!(
+                    "Box {:?} at byte {pos} of {box_length} bytes extends past the end of the {} byte file",
+                    type_name(&box_type),
+                    data.len()
+                ))
+            })?;
+        boxes.push(
+            JxlBox::new(box_type, data[pos + header_length..end].to_vec())
+                .with_large_header(header_length == 16)
+                .with_offset(pos),
+        );
+        pos = end;
+    }
+    if boxes.get(1).map(JxlBox::box_type) != Some(SIGNATURE_BOXES[1]) {
+        return Err(PyValueError::new_err(
+            "Container signature box is not followed by a file type box at byte 12",
+        ));
+    }
+    Ok(())
+}
+
+/// The boxes of the container `data`, signature and file type boxes included.
+///
+/// Malformed or truncated boxes raise `ValueError` when `strict`, and
+/// otherwise end the parse with a `JxlWarning`, keeping the boxes before them.
+pub(crate) fn read_boxes(data: &[u8], strict: bool) -> PyResult<Vec<JxlBox>> {
+    if !data.starts_with(JXL_CONTAINER_SIGNATURE) {
+        return Err(PyValueError::new_err(
+            "Data does not start with a JPEG XL container signature",
+        ));
+    }
+    let mut boxes = Vec::new();
+    match parse_boxes(data, &mut boxes) {
+        Ok(()) => {}
+        Err(e) if strict => return Err(e),
+        Err(e) => warn(&format!("Ignoring the rest of the container: {e}"))?,
+    }
+    Ok(boxes)
+}
+
+/// A `jxlc` box, or one of the `jxlp` boxes a codestream is split over.
+///
+/// Offsets are in the serialized container and in the reassembled codestream.
+#[pyclass(module = "pillow_jxl", frozen)]
+pub struct CodestreamPart {
+    #[pyo3(get)]
+    index: u32,
+    #[pyo3(get)]
+    is_last: bool,
+    #[pyo3(get)]
+    box_index: usize,
+    #[pyo3(get)]
+    file_offset: usize,
+    #[pyo3(get)]
+    codestream_offset: usize,
+    #[pyo3(get)]
+    size: usize,
+}
+
+#[pymethods]
+impl CodestreamPart {
+    fn __repr__(&self) -> String {
+        format!(
+            "CodestreamPart(index={}, is_last={}, box_index={}, file_offset={}, codestream_offset={}, size={})",
+            self.index,
+            self.is_last,
+            self.box_index,
+            self.file_offset,
+            self.codestream_offset,
+            self.size
+        )
+    }
+}
+
+/// The boxes of a JPEG XL container, which can be edited and written back
+/// out without touching the codestream.
+#[pyclass(module = "pillow_jxl")]
+pub struct JxlContainer {
+    boxes: Vec<JxlBox>,
+}
+
+#[pymethods]
+impl JxlContainer {
+    #[new]
+    #[pyo3(signature = (data, strict=true))]
+    fn new(data: &[u8], strict: bool) -> PyResult<Self> {
+        Ok(Self {
+            boxes: read_boxes(data, strict)?,
+        })
+    }
+
+    #[getter]
+    fn boxes(&self) -> Vec<JxlBox> {
+        self.boxes.clone()
+    }
+
+    fn __len__(&self) -> usize {
+        self.boxes.len()
+    }
+
+    /// First box of `box_type`, if any
+    fn find(&self, box_type: &Bound<'_, PyAny>) -> PyResult<Option<JxlBox>> {
+        let box_type = extract_box_type(box_type)?;
+        Ok(self
+            .boxes
+            .iter()
+            .find(|b| b.box_type() == &box_type)
+            .cloned())
+    }
+
+    /// Every box of `box_type`, in file order
+    fn find_all(&self, box_type: &Bound<'_, PyAny>) -> PyResult<Vec<JxlBox>> {
+        let box_type = extract_box_type(box_type)?;
+        Ok(self
+            .boxes
+            .iter()
+            .filter(|b| b.box_type() == &box_type)
+            .cloned()
+            .collect())
+    }
+
+    /// Insert a box at `index`, by default in front of the codestream
+    #[pyo3(signature = (box_type, data, index=None))]
+    fn add(
+        &mut self,
+        box_type: &Bound<'_, PyAny>,
+        data: Vec<u8>,
+        index: Option<usize>,
+    ) -> PyResult<()> {
+        let box_type = extract_box_type(box_type)?;
+        if SIGNATURE_BOXES.contains(&&box_type) {
+            return Err(PyValueError::new_err(format!(
+                "A container has exactly one {:?} box",
+                type_name(&box_type)
+            )));
+        }
+        let index = match index {
+            Some(index) if (SIGNATURE_BOXES.len()..=self.boxes.len()).contains(&index) => index,
+            Some(index) => {
+                return Err(PyValueError::new_err(format!(
+                    "Box index {index} must be between {} and {}",
+                    SIGNATURE_BOXES.len(),
+                    self.boxes.len()
+                )))
+            }
+            None => self
+                .boxes
+                .iter()
+                .position(|b| CODESTREAM_BOXES.contains(&b.box_type()))
+                .unwrap_or(self.boxes.len()),
+        };
+        self.boxes.insert(index, JxlBox::new(box_type, data));
+        Ok(())
+    }
+
+    /// Replace the contents of the first box of `box_type`
+    fn replace(&mut self, box_type: &Bound<'_, PyAny>, data: Vec<u8>) -> PyResult<()> {
+        let box_type = extract_box_type(box_type)?;
+        if SIGNATURE_BOXES.contains(&&box_type) {
+            return Err(PyValueError::new_err(format!(
+                "The {:?} box cannot be replaced",
+                type_name(&box_type)
+            )));
+        }
+        let Some(index) = self.boxes.iter().position(|b| b.box_type() == &box_type) else {
+            return Err(PyKeyError::new_err(format!(
+                "No {:?} box in the container",
+                type_name(&box_type)
+            )));
+        };
+        self.boxes[index] = JxlBox::new(box_type, data);
+        Ok(())
+    }
+
+    /// Remove every box of `box_type`, returning how many there were
+    fn remove(&mut self, box_type: &Bound<'_, PyAny>) -> PyResult<usize> {
+        let box_type = extract_box_type(box_type)?;
+        if SIGNATURE_BOXES.contains(&&box_type) {
+            return Err(PyValueError::new_err(format!(
+                "The {:?} box cannot be removed",
+                type_name(&box_type)
+            )));
+        }
+        let before = self.boxes.len();
+        self.boxes.retain(|b| b.box_type() != &box_type);
+        Ok(before - self.boxes.len())
+    }
+
+    /// The codestream, reassembled from `jxlp` parts if it is split
+    #[getter(codestream)]
+    fn py_codestream(&self) -> PyResult<Vec<u8>> {
+        Ok(self.codestream()?.into_owned())
+    }
+
+    /// Where each part of the codestream lies in the container and the codestream
+    #[getter(codestream_parts)]
+    fn py_codestream_parts(&self) -> PyResult<Vec<CodestreamPart>> {
+        codestream_parts(&self.boxes)
+    }
+
+    /// Serialize the boxes into a container file
+    fn tobytes(&self) -> Vec<u8> {
+        self.serialize()
+    }
+
+    fn __repr__(&self) -> String {
+        let types: Vec<_> = self
+            .boxes
+            .iter()
+            .map(|b| type_name(b.box_type()).into_owned())
+            .collect();
+        format!("JxlContainer(boxes={types:?})")
+    }
+}
+
+impl JxlContainer {
+    /// Remove boxes of any of `box_types`, including Brotli-compressed ones.
+    /// Returns how many boxes were removed.
+    fn strip(&mut self, box_types: &[[u8; 4]]) -> usize {
+        let before = self.boxes.len();
+        self.boxes.retain(|b| {
+            let box_type = match b.box_type() {
+                b"brob" => b.data().get(..4).unwrap_or_default(),
+                box_type => box_type,
+            };
+            !box_types.iter().any(|t| t == box_type)
+        });
+        let removed = before - self.boxes.len();
+        // JPEG reconstruction data refers to the Exif and XMP boxes, and
+        // can't be used to rebuild the JPEG once either of them is gone
+        if removed > 0 && box_types.iter().any(|t| t == b"Exif" || t == b"xml ") {
+            self.boxes.retain(|b| b.box_type() != b"jbrd");
+        }
+        before - self.boxes.len()
+    }
+
+    /// Whether the codestream is all that the container holds
+    fn only_codestream(&self) -> bool {
+        self.boxes[SIGNATURE_BOXES.len()..]
+            .iter()
+            .all(|b| CODESTREAM_BOXES.contains(&b.box_type()))
+    }
+
+    /// The codestream, from the `jxlc` box or the concatenated `jxlp` parts
+    fn codestream(&self) -> PyResult<Cow<'_, [u8]>> {
+        let parts = codestream_parts(&self.boxes)?;
+        let part_data = |part: &CodestreamPart| {
+            let data = self.boxes[part.box_index].data();
+            &data[data.len() - part.size..]
+        };
+        match &parts[..] {
+            [part] => Ok(Cow::Borrowed(part_data(part))),
+            parts => Ok(Cow::Owned(
+                parts.iter().flat_map(part_data).copied().collect(),
+            )),
+        }
+    }
+
+    pub(crate) fn serialize(&self) -> Vec<u8> {
+        let size = self.boxes.iter().map(|b| 16 + b.data().len()).sum();
+        let mut out = Vec::with_capacity(size);
+        for jxl_box in &self.boxes {
+            let data = jxl_box.data();
+            if header_size(jxl_box) == 8 {
+                out.extend_from_slice(&(8 + data.len() as u32).to_be_bytes());
+                out.extend_from_slice(jxl_box.box_type());
+            } else {
+                // A 64-bit size, given after the type
+                out.extend_from_slice(&1u32.to_be_bytes());
+                out.extend_from_slice(jxl_box.box_type());
+                out.extend_from_slice(&(16 + data.len() as u64).to_be_bytes());
+            }
+            out.extend_from_slice(data);
+        }
+        out
+    }
+}
+
+/// The boxes holding the codestream, checking that `jxlp` parts are
+/// numbered in order and that only the last one is flagged as such
+pub(crate) fn codestream_parts(boxes: &[JxlBox]) -> PyResult<Vec<CodestreamPart>> {
+    let mut parts: Vec<CodestreamPart> = Vec::new();
+    let mut has_jxlc = false;
+    let mut file_offset = 0;
+    let mut codestream_offset = 0;
+    for (box_index, jxl_box) in boxes.iter().enumerate() {
+        let data = jxl_box.data();
+        let box_offset = file_offset;
+        file_offset += header_size(jxl_box) + data.len();
+        let (index, is_last, header) = match jxl_box.box_type() {
+            b"jxlc" => (0, true, 0),
+            b"jxlp" => {
+                let Some(index) = data.get(..4) else {
+                    return Err(PyValueError::new_err(format!(
+                        "jxlp box at byte {box_offset} is too short for its sequence index"
+                    )));
+                };
+                let index = u32::from_be_bytes(index.try_into().unwrap());
+                (index & !LAST_PART_FLAG, index & LAST_PART_FLAG != 0, 4)
+            }
+            _ => continue,
+        };
+        if has_jxlc || (jxl_box.box_type() == b"jxlc" && !parts.is_empty()) {
+            return Err(PyValueError::new_err(format!(
+                "Codestream box at byte {box_offset} follows a jxlc box or is mixed with jxlp boxes"
+            )));
+        }
+        has_jxlc = jxl_box.box_type() == b"jxlc";
+        if let Some(previous) = parts.last() {
+            if previous.is_last {
+                return Err(PyValueError::new_err(format!(
+                    "jxlp box at byte {box_offset} follows the last codestream part"
+                )));
+            }
+        }
+        if index as usize != parts.len() {
+            return Err(PyValueError::new_err(format!(
+                "jxlp box at byte {box_offset} has sequence index {index}, expected {}",
+                parts.len()
+            )));
+        }
+        let size = data.len() - header;
+        parts.push(CodestreamPart {
+            index,
+            is_last,
+            box_index,
+            file_offset: box_offset + header_size(jxl_box) + header,
+            codestream_offset,
+            size,
+        });
+        codestream_offset += size;
+    }
+    match parts.last() {
+        None => Err(PyValueError::new_err("Container has no codestream")),
+        Some(part) if !part.is_last => Err(PyValueError::new_err(format!(
+            "Last jxlp box, with sequence index {}, is not flagged as the last part",
+            part.index
+        ))),
+        Some(_) => Ok(parts),
+    }
+}
+
+/// Remove metadata boxes from a JPEG XL file without touching the codestream.
+///
+/// Exif, XMP and JUMBF boxes are removed by default. A container left with
+/// nothing but its codestream is converted to a bare codestream unless
+/// `keep_container` is set. Bare codestreams are returned unchanged.
+#[pyfunction]
+#[pyo3(signature = (data, box_types=None, keep_container=false))]
+pub fn strip_metadata(
+    data: &[u8],
+    box_types: Option<Vec<Bound<'_, PyAny>>>,
+    keep_container: bool,
+) -> PyResult<Vec<u8>> {
+    if data.starts_with(JXL_CODESTREAM_SIGNATURE) {
+        return Ok(data.to_vec());
+    }
+    let box_types = match box_types {
+        Some(box_types) => box_types
+            .iter()
+            .map(extract_box_type)
+            .collect::<PyResult<Vec<_>>>()?,
+        None => METADATA_BOXES.map(|t| *t).to_vec(),
+    };
+    if let Some(box_type) = box_types
+        .iter()
+        .find(|t| SIGNATURE_BOXES.contains(t) || CODESTREAM_BOXES.contains(t))
+    {
+        return Err(PyValueError::new_err(format!(
+            "The {:?} box cannot be stripped",
+            type_name(box_type)
+        )));
+    }
+    let mut container = JxlContainer::new(data, true)?;
+    container.strip(&box_types);
+    if container.only_codestream() && !keep_container {
+        return Ok(container.codestream()?.into_owned());
+    }
+    Ok(container.serialize())
+}
+
+/// Wrap a bare codestream into a container, optionally with a `jxll` level
+/// box and metadata boxes.
+#[pyfunction]
+#[pyo3(signature = (data, level=None, exif=None, xmp=None, jumb=None))]
+pub fn wrap_codestream(
+    data: &[u8],
+    level: Option<u8>,
+    exif: Option<&[u8]>,
+    xmp: Option<&[u8]>,
+    jumb: Option<&[u8]>,
+) -> PyResult<Vec<u8>> {
+    if !data.starts_with(JXL_CODESTREAM_SIGNATURE) {
+        return Err(PyValueError::new_err(
+            "Data is not a bare JPEG XL codestream",
+        ));
+    }
+    let mut boxes = vec![
+        JxlBox::new(*SIGNATURE_BOXES[0], SIGNATURE_CONTENTS.to_vec()),
+        JxlBox::new(*SIGNATURE_BOXES[1], FTYP_CONTENTS.to_vec()),
+    ];
+    if let Some(level) = level {
+        boxes.push(JxlBox::new(*b"jxll", vec![level::validate(level)?]));
+    }
+    if let Some(exif) = exif {
+        boxes.push(JxlBox::new(*b"Exif", exif::to_box(exif)?.into_owned()));
+    }
+    if let Some(xmp) = xmp {
+        boxes.push(JxlBox::new(*b"xml ", xmp.to_vec()));
+    }
+    if let Some(jumb) = jumb {
+        boxes.push(JxlBox::new(*b"jumb", jumb.to_vec()));
+    }
+    boxes.push(JxlBox::new(*b"jxlc", data.to_vec()));
+    Ok(JxlContainer { boxes }.serialize())
+}
+
+/// The bare codestream of a container, concatenating `jxlp` parts.
+/// Every other box is dropped; bare codestreams are returned unchanged.
+///
+/// Containers declaring a level other than 5 are rejected, as a bare
+/// codestream can't signal it and is taken to be level 5.
+#[pyfunction]
+pub fn unwrap_container(data: &[u8]) -> PyResult<Vec<u8>> {
+    if data.starts_with(JXL_CODESTREAM_SIGNATURE) {
+        return Ok(data.to_vec());
+    }
+    let container = JxlContainer::new(data, true)?;
+    if let Some(level) = declared_level(&container.boxes).filter(|&l| l != level::DEFAULT_LEVEL) {
+        return Err(PyValueError::new_err(format!(
+            "The container declares codestream level {level}, which a bare codestream \
+             cannot signal"
+        )));
+    }
+    Ok(container.codestream()?.into_owned())
+}
+
+/// The level declared by the first `jxll` box among `boxes`, if any
+pub(crate) fn declared_level(boxes: &[JxlBox]) -> Option<u8> {
+    boxes
+        .iter()
+        .find(|b| b.box_type() == b"jxll")
+        .and_then(|b| b.data().first().copied())
+}
+
+/// The codestream level of the file `data`
+pub(crate) fn file_level(data: &[u8]) -> PyResult<u8> {
+    if data.starts_with(JXL_CODESTREAM_SIGNATURE) {
+        return Ok(level::DEFAULT_LEVEL);
+    }
+    let container = JxlContainer::new(data, true)?;
+    Ok(declared_level(&container.boxes).unwrap_or(level::DEFAULT_LEVEL))
+}
+
+/// `data` with a `jxll` box declaring `level`, wrapping a bare codestream
+/// into a container first
+pub(crate) fn declare_level(data: Vec<u8>, level: u8) -> PyResult<Vec<u8>> {
+    if data.starts_with(JXL_CODESTREAM_SIGNATURE) {
+        return wrap_codestream(&data, Some(level), None, None, None);
+    }
+    let mut container = JxlContainer::new(&data, true)?;
+    let level_box = JxlBox::new(*b"jxll", vec![level]);
+    match container.boxes.iter().position(|b| b.box_type() == b"jxll") {
+        Some(index) => container.boxes[index] = level_box,
+        // The level box goes right after the file type box
+        None => container.boxes.insert(SIGNATURE_BOXES.len(), level_box),
+    }
+    Ok(container.serialize())
+}
@@ -1,12 +1,37 @@
 use std::borrow::Cow;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Instant;
 
 use pyo3::exceptions::{PyNotImplementedError, PyRuntimeError, PyValueError};
 use pyo3::prelude::*;
+use pyo3::pybacked::PyBackedBytes;
 
+use half::slice::HalfFloatSliceExt;
 use jpegxl_rs::decode::{Data, Metadata, Pixels};
 use jpegxl_rs::parallel::threads_runner::ThreadsRunner;
 use jpegxl_rs::{decoder_builder, DecodeError};
 
+use crate::batch;
+use crate::buffer::{PixelBuffer, Samples};
+use crate::chunked;
+use crate::container;
+use crate::exif;
+use crate::format::BufferFormat;
+use crate::level;
+use crate::logging::{self, Level};
+use crate::mmap::MappedFile;
+use crate::runner::{PooledRunner, RunnerPool};
+use crate::warn;
+
+mod boxes;
+mod out_buffer;
+mod quantize;
+mod srgb;
+
+use quantize::{Dither, Quantizer};
+pub(crate) use srgb::FloatImage;
+
 // it works even if the item is not documented:
 
 #[pyclass(module = "pillow_jxl")]
@@ -21,6 +46,16 @@ struct ImageInfo {
     num_channels: u32, // Number of color channels per pixel
     #[pyo3(get, set)]
     has_alpha_channel: bool,
+    #[pyo3(get, set)]
+    exif: Option<Vec<u8>>, // TIFF data of the first `Exif` box, as Pillow expects
+    #[pyo3(get, set)]
+    xmp: Option<Vec<u8>>, // Payload of the first `xml ` box
+    #[pyo3(get, set)]
+    jumbf: Option<Vec<u8>>, // Payload of the first `jumb` box, e.g. C2PA
+    #[pyo3(get, set)]
+    level: u8, // Codestream level declared by the `jxll` box, 5 without one
+    #[pyo3(get, set)]
+    exceeds_level5: bool, // Whether the image is beyond the limits of level 5
 }
 
 impl ImageInfo {
@@ -29,15 +64,58 @@ impl ImageInfo {
             Data::Pixels(pixels) => Some(pixels),
             Data::Jpeg(_) => None,
         };
+        // JPEG reconstructions are always 8 bit
+        let bits_per_sample = match pixel_type {
+            Some(Pixels::Uint8(_)) | None => 8,
+            Some(Pixels::Uint16(_) | Pixels::Float16(_)) => 16,
+            Some(Pixels::Float(_)) => 32,
+        };
+        let icc_size = item.icc_profile.as_ref().map_or(0, Vec::len);
         ImageInfo {
             mode: Self::mode(item.num_color_channels, item.has_alpha_channel, pixel_type).unwrap(),
             width: item.width,
             height: item.height,
             num_channels: item.num_color_channels,
             has_alpha_channel: item.has_alpha_channel,
+            exif: None,
+            xmp: None,
+            jumbf: None,
+            level: level::DEFAULT_LEVEL,
+            exceeds_level5: level::level5_violation(
+                item.width,
+                item.height,
+                bits_per_sample,
+                icc_size,
+            )
+            .is_some(),
         }
     }
 
+    /// Take the metadata fields from the first box of each metadata type.
+    /// Invalid Exif boxes raise `ValueError` when `strict` and are skipped
+    /// with a warning otherwise.
+    fn with_metadata(mut self, boxes: &[JxlBox], strict: bool) -> PyResult<Self> {
+        self.level = container::declared_level(boxes).unwrap_or(level::DEFAULT_LEVEL);
+        for jxl_box in boxes {
+            let field = match &jxl_box.box_type {
+                b"Exif" if self.exif.is_none() => {
+                    match exif::from_box(&jxl_box.data).map_err(|e| jxl_box.error(e)) {
+                        Ok(tiff) => self.exif = Some(tiff.to_vec()),
+                        Err(e) if strict => return Err(e),
+                        Err(e) => warn(&format!("Ignoring invalid Exif box: {e}"))?,
+                    }
+                    continue;
+                }
+                b"Exif" => continue,
+                b"xml " => &mut self.xmp,
+                b"jumb" => &mut self.jumbf,
+                _ => continue,
+            };
+            field.get_or_insert_with(|| jxl_box.data.clone());
+        }
+        Ok(self)
+    }
+
     fn mode(
         num_channels: u32,
         has_alpha_channel: bool,
@@ -71,189 +149,247 @@ impl ImageInfo {
     }
 }
 
-#[pyclass(module = "pillow_jxl")]
+#[pyclass(module = "pillow_jxl", skip_from_py_object)]
+#[derive(Clone)]
 pub struct JxlBox {
     #[pyo3(get, set)]
     box_type: [u8; 4],
     #[pyo3(get, set)]
     data: Vec<u8>,
+    /// Whether the box was stored Brotli-compressed in a `brob` box
+    #[pyo3(get, set)]
+    compressed: bool,
+    /// Whether the box was read with a 64-bit size, which writing it keeps
+    large_header: bool,
+    /// Where the box starts in the file it was read from
+    offset: Option<usize>,
 }
 
-#[pymethods]
 impl JxlBox {
-    fn __repr__(&self) -> PyResult<String> {
-        Ok(format!(
-            "JxlBox(type={:?}, size={})",
-            String::from_utf8_lossy(&self.box_type),
-            self.data.len()
-        ))
+    pub(crate) fn new(box_type: [u8; 4], data: Vec<u8>) -> Self {
+        Self {
+            box_type,
+            data,
+            compressed: false,
+            large_header: false,
+            offset: None,
+        }
     }
-}
 
-// refer to https://github.com/Fraetor/jxl_decode/blob/902cd5d479f89f93df6105a22dc92f297ab77541/src/jxl_decode/jxl.py#L88-L110
-fn extract_boxes(data: &[u8]) -> PyResult<Vec<JxlBox>> {
-    const JXL_CONTAINER_SIGNATURE: &[u8] = b"\x00\x00\x00\x0c\x4a\x58\x4c\x20\x0d\x0a\x87\x0a";
-    if !data.starts_with(JXL_CONTAINER_SIGNATURE) {
-        return Ok(Vec::new());
+    pub(crate) fn with_offset(mut self, offset: usize) -> Self {
+        self.offset = Some(offset);
+        self
     }
 
-    const JXL_CONTAINER_HEADER_SIZE: usize = 32;
-    let mut boxes = Vec::new();
-    let mut pos = JXL_CONTAINER_HEADER_SIZE;
+    pub(crate) fn with_large_header(mut self, large_header: bool) -> Self {
+        self.large_header = large_header;
+        self
+    }
 
-    while pos + 8 <= data.len() {
-        // A box has at least a 4-byte size and 4-byte type.
-        let box_size = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+    pub(crate) fn large_header(&self) -> bool {
+        self.large_header
+    }
 
-        let (header_length, box_length) = if box_size == 1 {
-            // 64-bit box size
-            if pos + 16 > data.len() {
-                // Not enough data for a large box header
-                break;
-            }
-            let large_box_size = u64::from_be_bytes(data[pos + 8..pos + 16].try_into().unwrap());
-            let large_box_size = usize::try_from(large_box_size).map_err(|_| {
-                PyValueError::new_err(format!(
-                    "Box size at position {pos} is too large for this platform"
-                ))
-            })?;
-            if large_box_size < 16 {
-                // The box is smaller than its own header, which is invalid.
-                return Err(PyValueError::new_err(format!(
-                    "Invalid large box size at position {pos}",
-                )));
-            }
-            (16, large_box_size)
-        } else {
-            // 32-bit box size
-            if (2..=7).contains(&box_size) {
-                return Err(PyValueError::new_err(format!(
-                    "Invalid box size at position {pos}",
-                )));
-            }
-            (
-                8,
-                if box_size == 0 {
-                    data.len() - pos
-                } else {
-                    box_size
-                },
-            )
+    /// `e`, of the same exception type, saying which box it is about
+    pub(crate) fn error(&self, e: PyErr) -> PyErr {
+        let location = match self.offset {
+            Some(offset) => format!(" at byte {offset}"),
+            None => String::new(),
         };
+        Python::attach(|py| {
+            let err = PyErr::from_type(
+                e.get_type(py),
+                format!(
+                    "Box {:?}{location}: {}",
+                    String::from_utf8_lossy(&self.box_type),
+                    e.value(py)
+                ),
+            );
+            err.set_cause(py, Some(e));
+            err
+        })
+    }
 
-        if pos
-            .checked_add(box_length)
-            .is_none_or(|end| end > data.len())
-        {
-            // Box extends beyond the end of the data, which indicates a corrupt file.
-            break;
-        }
+    pub(crate) fn box_type(&self) -> &[u8; 4] {
+        &self.box_type
+    }
 
-        let box_type = data[pos + 4..pos + 8].try_into().unwrap();
-        let box_data = data[pos + header_length..pos + box_length].to_vec();
+    pub(crate) fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
 
-        boxes.push(JxlBox {
-            box_type,
-            data: box_data,
-        });
+#[pymethods]
+impl JxlBox {
+    fn __repr__(&self) -> PyResult<String> {
+        Ok(format!(
+            "JxlBox(type={:?}, size={}, compressed={})",
+            String::from_utf8_lossy(&self.box_type),
+            self.data.len(),
+            self.compressed
+        ))
+    }
+}
 
-        pos += box_length;
+pub(crate) const JXL_CODESTREAM_SIGNATURE: &[u8] = b"\xff\x0a";
+pub(crate) const JXL_CONTAINER_SIGNATURE: &[u8] =
+    b"\x00\x00\x00\x0c\x4a\x58\x4c\x20\x0d\x0a\x87\x0a";
+
+/// Whether `data` starts with a JPEG XL codestream or container signature.
+pub(crate) fn is_jxl(data: &[u8]) -> bool {
+    data.starts_with(JXL_CODESTREAM_SIGNATURE) || data.starts_with(JXL_CONTAINER_SIGNATURE)
+}
+
+/// The boxes following the signature and file type boxes of a container;
+/// a bare codestream has none.
+fn extract_boxes(data: &[u8], strict: bool) -> PyResult<Vec<JxlBox>> {
+    if !data.starts_with(JXL_CONTAINER_SIGNATURE) {
+        return Ok(Vec::new());
+    }
+    let mut boxes = container::read_boxes(data, strict)?;
+    if strict {
+        container::codestream_parts(&boxes)?;
     }
+    boxes.retain(|b| &b.box_type != b"JXL " && &b.box_type != b"ftyp");
     Ok(boxes)
 }
 
 #[pyclass(module = "pillow_jxl")]
 pub struct Decoder {
-    num_threads: isize,
+    runners: RunnerPool,
+    format: BufferFormat,
+    quantizer: Quantizer,
+    strict: bool,
+    /// Wide samples `decode_into` reduces to 8 bits, kept to reuse their allocation
+    scratch: Mutex<Option<Pixels>>,
 }
 
-type DecodeResult<'a> = (bool, ImageInfo, Cow<'a, [u8]>, Cow<'a, [u8]>, Vec<JxlBox>);
+type DecodeResult<'a> = (bool, ImageInfo, PixelBuffer, Cow<'a, [u8]>, Vec<JxlBox>);
 
 #[pymethods]
 impl Decoder {
     #[new]
-    #[pyo3(signature = (num_threads = -1))]
-    fn new(num_threads: isize) -> Self {
-        Self { num_threads }
+    #[pyo3(signature = (num_threads = -1, channel_order = "RGB", endianness = "native", dither = "none", clamp = true, strict = false))]
+    fn new(
+        num_threads: isize,
+        channel_order: &str,
+        endianness: &str,
+        dither: &str,
+        clamp: bool,
+        strict: bool,
+    ) -> PyResult<Self> {
+        Ok(Self {
+            format: BufferFormat::new(channel_order, endianness)?,
+            quantizer: Quantizer {
+                dither: Dither::from_name(dither)?,
+                clamp,
+            },
+            strict,
+            ..Self::with_threads(num_threads)
+        })
     }
 
     #[pyo3(signature = (data))]
     fn __call__(&self, _py: Python, data: &[u8]) -> PyResult<DecodeResult<'_>> {
-        _py.detach(|| self.call_inner(data))
+        logging::detach(_py, || self.call_inner(data))
+    }
+
+    #[pyo3(signature = (data))]
+    fn decode_array(&self, py: Python, data: &[u8]) -> PyResult<PixelBuffer> {
+        logging::detach(py, || {
+            let parallel_runner = self.parallel_runner()?;
+            let decoder = decoder_builder()
+                .parallel_runner(&*parallel_runner)
+                .build()
+                .map_err(to_pyjxlerror)?;
+            let (info, pixels) = decoder.decode(data).map_err(to_pyjxlerror)?;
+            // Unlike `__call__`, keep every channel at its native sample type
+            let samples = match pixels {
+                Pixels::Uint8(pixels) => Samples::Uint8(pixels),
+                Pixels::Uint16(pixels) => Samples::Uint16(pixels),
+                Pixels::Float16(pixels) => Samples::Float16(pixels),
+                Pixels::Float(pixels) => Samples::Float32(pixels),
+            };
+            chunked::with_runner(&parallel_runner, || {
+                self.pixel_buffer(samples, info.width, info.height, info.has_alpha_channel)
+            })
+        })
+    }
+
+    #[pyo3(signature = (data, max_workers=None))]
+    fn decode_many<'py>(
+        &self,
+        py: Python<'py>,
+        data: Vec<PyBackedBytes>,
+        max_workers: Option<usize>,
+    ) -> PyResult<Vec<Bound<'py, PyAny>>> {
+        let results = logging::detach(py, || {
+            batch::run(&data, max_workers, |runner, data| {
+                self.decode_with(runner, data)
+            })
+        })?;
+        batch::into_pylist(py, results)
+    }
+
+    #[pyo3(signature = (data, out))]
+    fn decode_into(&self, py: Python, data: &[u8], out: Py<PyAny>) -> PyResult<ImageInfo> {
+        logging::detach(py, || self.decode_into_inner(data, &out))
+    }
+
+    #[pyo3(signature = (path))]
+    fn decode_file(&self, py: Python, path: PathBuf) -> PyResult<DecodeResult<'_>> {
+        logging::detach(py, || {
+            let data = MappedFile::open(&path)?;
+            self.call_inner(&data)
+        })
     }
 
     fn __repr__(&self) -> PyResult<String> {
-        Ok("Decoder".to_string())
+        Ok(format!(
+            "Decoder(channel_order={:?}, endianness={:?}, dither={:?}, clamp={}, strict={})",
+            self.format.channel_order.name(),
+            self.format.endianness.name(),
+            self.quantizer.dither.name(),
+            self.quantizer.clamp,
+            self.strict
+        ))
     }
 }
 
 impl Decoder {
-    fn pixels_to_bytes_8bit(&self, pixels: Pixels) -> PyResult<Vec<u8>> {
-        // Convert pixels to bytes with 8-bit casting
-        let mut result = Vec::new();
-        match pixels {
-            Pixels::Uint8(pixels) => {
-                return Ok(pixels);
-            }
-            Pixels::Uint16(pixels) => {
-                for pixel in pixels {
-                    result.push((pixel >> 8) as u8);
-                }
-            }
-            Pixels::Float(pixels) => {
-                for pixel in pixels {
-                    result.push((pixel * 255.0) as u8);
-                }
-            }
-            Pixels::Float16(pixels) => {
-                for pixel in pixels {
-                    // PERF: use native f16 ops
-                    result.push((pixel.to_f32() * 255.0) as u8);
-                }
-            }
-        }
-        Ok(result)
-    }
-
-    fn pixels_to_bytes(&self, pixels: Pixels) -> PyResult<Vec<u8>> {
-        // Convert pixels to bytes without casting
-        let mut result = Vec::new();
-        match pixels {
-            Pixels::Uint8(pixels) => {
-                return Ok(pixels);
-            }
-            Pixels::Uint16(pixels) => {
-                for pixel in pixels {
-                    let pix_bytes = pixel.to_ne_bytes();
-                    for byte in pix_bytes.iter() {
-                        result.push(*byte);
-                    }
-                }
-            }
-            Pixels::Float(pixels) => {
-                for pixel in pixels {
-                    let pix_bytes = pixel.to_ne_bytes();
-                    for byte in pix_bytes.iter() {
-                        result.push(*byte);
-                    }
-                }
-            }
+    fn pixels_to_samples(&self, pixels: Pixels) -> PyResult<Samples> {
+        // Keep the samples in their native type without casting
+        let result = match pixels {
+            Pixels::Uint8(pixels) => Samples::Uint8(pixels),
+            Pixels::Uint16(pixels) => Samples::Uint16(pixels),
+            Pixels::Float(pixels) => Samples::Float32(pixels),
+            // HACK: Pillow doesn't natively support float16 mode.
+            // Therefore, you have to upcast
             Pixels::Float16(pixels) => {
-                // HACK: Pillow doesn't natively support float16 mode.
-                // Therefore, you have to upcast
-                for pixel in pixels {
-                    let pix_bytes = pixel.to_f32().to_ne_bytes();
-                    result.extend_from_slice(&pix_bytes);
-                }
+                let mut widened = vec![0.0; pixels.len()];
+                chunked::zip_rows(&pixels, &mut widened, 1, |_, input, output| {
+                    input.convert_to_f32_slice(output)
+                })?;
+                Samples::Float32(widened)
             }
-        }
+        };
         Ok(result)
     }
 
-    fn convert_pil_pixels(&self, pixels: Pixels, num_channels: u32) -> PyResult<Vec<u8>> {
+    fn convert_pil_pixels(
+        &self,
+        pixels: Pixels,
+        width: u32,
+        num_channels: u32,
+        has_alpha: bool,
+    ) -> PyResult<Samples> {
         let result = match num_channels {
-            1 => self.pixels_to_bytes(pixels)?,
-            3 => self.pixels_to_bytes_8bit(pixels)?,
+            1 => self.pixels_to_samples(pixels)?,
+            3 => Samples::Uint8(self.quantizer.reduce(
+                pixels,
+                width as usize,
+                (num_channels + u32::from(has_alpha)) as usize,
+            )?),
             _ => return Err(PyValueError::new_err("image color channels must be 1 or 3")),
         };
         Ok(result)
@@ -261,45 +397,111 @@ impl Decoder {
 }
 
 impl Decoder {
+    pub(crate) fn with_threads(num_threads: isize) -> Self {
+        Self {
+            runners: RunnerPool::new(num_threads),
+            format: BufferFormat::default(),
+            quantizer: Quantizer::default(),
+            strict: false,
+            scratch: Mutex::new(None),
+        }
+    }
+
+    /// Wrap decoded RGB(A) samples, converted to the requested channel and byte order
+    fn pixel_buffer(
+        &self,
+        mut samples: Samples,
+        width: u32,
+        height: u32,
+        has_alpha: bool,
+    ) -> PyResult<PixelBuffer> {
+        if !self.format.is_native() {
+            let num_channels = samples.len() / (width as usize * height as usize).max(1);
+            let sample_size = samples.item_size();
+            self.format.rgb_to_buffer(
+                samples.as_bytes_mut(),
+                sample_size,
+                num_channels,
+                has_alpha,
+            )?;
+        }
+        Ok(PixelBuffer::image(samples, width, height).with_endianness(self.format.endianness))
+    }
+
+    /// The boxes of `data` after its signature, with Brotli compressed ones
+    /// decompressed
+    fn metadata_boxes(&self, data: &[u8]) -> PyResult<Vec<JxlBox>> {
+        let mut boxes = extract_boxes(data, self.strict)?;
+        match boxes::decompress_boxes(data, &mut boxes) {
+            Ok(()) => {}
+            Err(e) if self.strict => return Err(e),
+            Err(e) => warn(&format!("Failed to decompress JXL boxes: {e}"))?,
+        }
+        Ok(boxes)
+    }
+
+    pub(crate) fn parallel_runner(&self) -> PyResult<PooledRunner<'_>> {
+        self.runners.get()
+    }
+
     fn call_inner(&self, data: &[u8]) -> PyResult<DecodeResult<'_>> {
-        let parallel_runner = ThreadsRunner::new(
-            None,
-            if self.num_threads < 0 {
-                None
-            } else {
-                Some(self.num_threads as usize)
-            },
-        )
-        .ok_or_else(|| PyRuntimeError::new_err("Could not create JxlThreadsRunner"))?;
+        let parallel_runner = self.parallel_runner()?;
+        self.decode_with(&parallel_runner, data)
+    }
+
+    fn decode_with(
+        &self,
+        parallel_runner: &ThreadsRunner,
+        data: &[u8],
+    ) -> PyResult<DecodeResult<'static>> {
+        let start = Instant::now();
         let decoder = decoder_builder()
             .icc_profile(true)
-            .parallel_runner(&parallel_runner)
+            .parallel_runner(parallel_runner)
             .build()
             .map_err(to_pyjxlerror)?;
         let (info, img) = decoder.reconstruct(data).map_err(to_pyjxlerror)?;
-        let boxes = match extract_boxes(data) {
-            Ok(b) => b,
-            Err(e) => {
-                eprintln!("Warning: Failed to extract JXL boxes: {e}");
-                Vec::new()
-            }
-        };
+        let boxes = self.metadata_boxes(data)?;
         let icc_profile: Vec<u8> = match &info.icc_profile {
             Some(x) => x.to_vec(),
             None => Vec::new(),
         };
-        let img_info = ImageInfo::from(info, &img);
+        let img_info = ImageInfo::from(info, &img).with_metadata(&boxes, self.strict)?;
         let (jpeg, img) = match img {
-            Data::Jpeg(x) => (true, x),
-            Data::Pixels(x) => (false, self.convert_pil_pixels(x, img_info.num_channels)?),
+            Data::Jpeg(x) => (true, PixelBuffer::from_bytes(x)),
+            Data::Pixels(x) => (
+                false,
+                chunked::with_runner(parallel_runner, || {
+                    self.pixel_buffer(
+                        self.convert_pil_pixels(
+                            x,
+                            img_info.width,
+                            img_info.num_channels,
+                            img_info.has_alpha_channel,
+                        )?,
+                        img_info.width,
+                        img_info.height,
+                        img_info.has_alpha_channel,
+                    )
+                })?,
+            ),
         };
-        Ok((
-            jpeg,
-            img_info,
-            Cow::Owned(img),
-            Cow::Owned(icc_profile),
-            boxes,
-        ))
+        logging::log(Level::Debug, || {
+            let box_types: Vec<_> = boxes
+                .iter()
+                .map(|b| String::from_utf8_lossy(&b.box_type).into_owned())
+                .collect();
+            format!(
+                "Decoded {}x{} {} {} from {} bytes in {:.1} ms, boxes {box_types:?}",
+                img_info.width,
+                img_info.height,
+                img_info.mode,
+                if jpeg { "JPEG reconstruction" } else { "image" },
+                data.len(),
+                logging::millis(start.elapsed())
+            )
+        });
+        Ok((jpeg, img_info, img, Cow::Owned(icc_profile), boxes))
     }
 }
 
@@ -0,0 +1,124 @@
+//! Transparent decompression of Brotli-compressed (`brob`) metadata boxes.
+
+use std::ptr::null;
+
+use pyo3::prelude::*;
+
+use jpegxl_rs::DecodeError;
+use jpegxl_sys::common::types::{JxlBool, JxlBoxType};
+use jpegxl_sys::decode::{
+    JxlDecoderCloseInput, JxlDecoderCreate, JxlDecoderGetBoxType, JxlDecoderProcessInput,
+    JxlDecoderReleaseBoxBuffer, JxlDecoderSetBoxBuffer, JxlDecoderSetDecompressBoxes,
+    JxlDecoderSetInput, JxlDecoderStatus, JxlDecoderSubscribeEvents,
+};
+
+use super::out_buffer::{check, RawDecoder};
+use super::{to_pyjxlerror, JxlBox};
+
+/// Initial output buffer size for a decompressed box
+const BOX_BUFFER_SIZE: usize = 64 * 1024;
+
+/// A `brob` box being decompressed into `data`
+struct PendingBox {
+    box_type: [u8; 4],
+    data: Vec<u8>,
+    /// Bytes of `data` written by libjxl so far
+    written: usize,
+}
+
+impl PendingBox {
+    /// Hand the unwritten tail of the buffer to libjxl
+    fn lend(&mut self, dec: &RawDecoder) -> PyResult<()> {
+        if self.written == self.data.len() {
+            self.data.resize(self.data.len() * 2, 0);
+        }
+        let tail = &mut self.data[self.written..];
+        check(unsafe { JxlDecoderSetBoxBuffer(dec.0, tail.as_mut_ptr(), tail.len()) })
+    }
+
+    /// Take the buffer back from libjxl, accounting for what it wrote
+    fn release(&mut self, dec: &RawDecoder) {
+        let unwritten = unsafe { JxlDecoderReleaseBoxBuffer(dec.0) };
+        self.written = self.data.len() - unwritten;
+    }
+}
+
+fn box_type(dec: &RawDecoder, decompressed: bool) -> PyResult<[u8; 4]> {
+    let mut box_type = JxlBoxType([0; 4]);
+    check(unsafe { JxlDecoderGetBoxType(dec.0, &mut box_type, JxlBool::from(decompressed)) })?;
+    Ok(box_type.0.map(|c| c as u8))
+}
+
+/// Decompress every `brob` box of `data`, in file order, into the inner box
+/// type and contents of each. On failure, the box at fault is the one after
+/// those in `decompressed`.
+fn decompress_brob_boxes(data: &[u8], decompressed: &mut Vec<([u8; 4], Vec<u8>)>) -> PyResult<()> {
+    let dec = RawDecoder(unsafe { JxlDecoderCreate(null()) });
+    if dec.0.is_null() {
+        return Err(to_pyjxlerror(DecodeError::CannotCreateDecoder));
+    }
+    unsafe {
+        check(JxlDecoderSubscribeEvents(
+            dec.0,
+            JxlDecoderStatus::Box as i32,
+        ))?;
+        check(JxlDecoderSetDecompressBoxes(dec.0, JxlBool::True))?;
+        check(JxlDecoderSetInput(dec.0, data.as_ptr(), data.len()))?;
+        JxlDecoderCloseInput(dec.0);
+    }
+
+    let mut pending: Option<PendingBox> = None;
+    loop {
+        let status = unsafe { JxlDecoderProcessInput(dec.0) };
+        if matches!(status, JxlDecoderStatus::Box | JxlDecoderStatus::Success) {
+            if let Some(mut done) = pending.take() {
+                done.release(&dec);
+                done.data.truncate(done.written);
+                decompressed.push((done.box_type, done.data));
+            }
+        }
+        match status {
+            JxlDecoderStatus::Box => {
+                if &box_type(&dec, false)? == b"brob" {
+                    let mut next = PendingBox {
+                        box_type: box_type(&dec, true)?,
+                        data: vec![0; BOX_BUFFER_SIZE],
+                        written: 0,
+                    };
+                    next.lend(&dec)?;
+                    pending = Some(next);
+                }
+            }
+            JxlDecoderStatus::BoxNeedMoreOutput => {
+                let Some(pending) = pending.as_mut() else {
+                    return Err(to_pyjxlerror(DecodeError::InternalError(
+                        "box output requested without a box buffer",
+                    )));
+                };
+                pending.release(&dec);
+                pending.lend(&dec)?;
+            }
+            JxlDecoderStatus::Success => return Ok(()),
+            _ => return Err(to_pyjxlerror(DecodeError::GenericError)),
+        }
+    }
+}
+
+/// Replace `brob` boxes by their decompressed contents, keeping the inner type.
+pub(super) fn decompress_boxes(data: &[u8], boxes: &mut [JxlBox]) -> PyResult<()> {
+    if !boxes.iter().any(|b| &b.box_type == b"brob") {
+        return Ok(());
+    }
+    let mut decompressed = Vec::new();
+    let result = decompress_brob_boxes(data, &mut decompressed);
+    let mut brob_boxes = boxes.iter_mut().filter(|b| &b.box_type == b"brob");
+    for ((box_type, data), jxl_box) in decompressed.into_iter().zip(&mut brob_boxes) {
+        jxl_box.box_type = box_type;
+        jxl_box.data = data;
+        jxl_box.compressed = true;
+    }
+    match (result, brob_boxes.next()) {
+        (Err(e), Some(failed)) => Err(failed.error(e)),
+        (result, _) => result,
+    }
+}
@@ -0,0 +1,318 @@
+//! Decoding into caller-owned memory through libjxl's image-out buffer.
+
+use std::mem::MaybeUninit;
+use std::ptr::null;
+use std::sync::PoisonError;
+
+use pyo3::buffer::PyUntypedBuffer;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+use jpegxl_rs::decode::Pixels;
+use jpegxl_rs::parallel::ParallelRunner;
+use jpegxl_rs::DecodeError;
+use jpegxl_sys::common::types::{JxlDataType, JxlEndianness, JxlPixelFormat};
+use jpegxl_sys::decode::{
+    JxlColorProfileTarget, JxlDecoder, JxlDecoderCloseInput, JxlDecoderCreate, JxlDecoderDestroy,
+    JxlDecoderGetBasicInfo, JxlDecoderGetICCProfileSize, JxlDecoderImageOutBufferSize,
+    JxlDecoderProcessInput, JxlDecoderSetImageOutBuffer, JxlDecoderSetInput,
+    JxlDecoderSetParallelRunner, JxlDecoderStatus, JxlDecoderSubscribeEvents, JxlSignature,
+    JxlSignatureCheck,
+};
+use jpegxl_sys::metadata::codestream_header::JxlBasicInfo;
+
+use super::{to_pyjxlerror, Decoder, ImageInfo};
+use crate::chunked;
+use crate::level;
+
+pub(super) struct RawDecoder(pub(super) *mut JxlDecoder);
+
+impl Drop for RawDecoder {
+    fn drop(&mut self) {
+        unsafe { JxlDecoderDestroy(self.0) };
+    }
+}
+
+pub(super) fn check(status: JxlDecoderStatus) -> PyResult<()> {
+    match status {
+        JxlDecoderStatus::Success => Ok(()),
+        _ => Err(to_pyjxlerror(DecodeError::GenericError)),
+    }
+}
+
+/// Pillow mode and sample type matching what `Decoder.__call__` returns, and
+/// the sample type to decode to before quantizing to 8 bits, if any
+fn output_format(
+    info: &JxlBasicInfo,
+) -> PyResult<(&'static str, JxlPixelFormat, Option<JxlDataType>)> {
+    let has_alpha = info.alpha_bits > 0;
+    let (mode, data_type) = match (
+        info.num_color_channels,
+        has_alpha,
+        info.bits_per_sample,
+        info.exponent_bits_per_sample,
+    ) {
+        (3, false, ..) => ("RGB", JxlDataType::Uint8),
+        (3, true, ..) => ("RGBA", JxlDataType::Uint8),
+        (1, true, ..) => ("LA", JxlDataType::Uint8),
+        (1, false, bits, 0) if bits <= 8 => ("L", JxlDataType::Uint8),
+        (1, false, bits, 0) if bits <= 16 => ("I;16", JxlDataType::Uint16),
+        // Float16 is upcast, as Pillow has no half float mode
+        (1, false, 16 | 32, _) => ("F", JxlDataType::Float),
+        (1, false, bits, _) => return Err(to_pyjxlerror(DecodeError::UnsupportedBitWidth(bits))),
+        _ => return Err(PyValueError::new_err("image color channels must be 1 or 3")),
+    };
+    // Like `__call__`, reduce wider samples with the decoder's dithering and clamping
+    let wide_type = match (
+        data_type,
+        info.bits_per_sample,
+        info.exponent_bits_per_sample,
+    ) {
+        (JxlDataType::Uint8, ..=8, 0) => None,
+        (JxlDataType::Uint8, ..=16, 0) => Some(JxlDataType::Uint16),
+        (JxlDataType::Uint8, ..) => Some(JxlDataType::Float),
+        _ => None,
+    };
+    Ok((
+        mode,
+        JxlPixelFormat {
+            num_channels: info.num_color_channels + u32::from(has_alpha),
+            data_type,
+            endianness: JxlEndianness::Native,
+            align: 0,
+        },
+        wide_type,
+    ))
+}
+
+/// `len` samples of `data_type` for libjxl to decode into, reusing `scratch`
+/// when it holds the same sample type
+fn wide_pixels(scratch: Option<Pixels>, data_type: JxlDataType, len: usize) -> Pixels {
+    match (scratch, data_type) {
+        (Some(Pixels::Uint16(mut samples)), JxlDataType::Uint16) => {
+            samples.resize(len, 0);
+            Pixels::Uint16(samples)
+        }
+        (_, JxlDataType::Uint16) => Pixels::Uint16(vec![0; len]),
+        (Some(Pixels::Float(mut samples)), _) => {
+            samples.resize(len, 0.0);
+            Pixels::Float(samples)
+        }
+        _ => Pixels::Float(vec![0.0; len]),
+    }
+}
+
+/// Borrow a writable, contiguous buffer able to hold `size` bytes of `data_type` samples
+fn out_buffer(
+    out: &Bound<'_, PyAny>,
+    data_type: JxlDataType,
+    size: usize,
+) -> PyResult<PyUntypedBuffer> {
+    let buffer = PyUntypedBuffer::get(out)?;
+    if buffer.readonly() {
+        return Err(PyValueError::new_err("Output buffer is read-only"));
+    }
+    if !buffer.is_c_contiguous() {
+        return Err(PyValueError::new_err("Output buffer must be C-contiguous"));
+    }
+    // Untyped byte buffers (bytearray, uint8 arrays) can hold any sample type
+    let compatible = buffer.as_typed::<u8>().is_ok()
+        || match data_type {
+            JxlDataType::Uint16 => buffer.as_typed::<u16>().is_ok(),
+            JxlDataType::Float => buffer.as_typed::<f32>().is_ok(),
+            _ => false,
+        };
+    if !compatible {
+        return Err(PyValueError::new_err(format!(
+            "Output buffer of format {:?} cannot hold {data_type:?} samples",
+            buffer.format()
+        )));
+    }
+    if buffer.len_bytes() != size {
+        return Err(PyValueError::new_err(format!(
+            "Output buffer must be {size} bytes, got {}",
+            buffer.len_bytes()
+        )));
+    }
+    Ok(buffer)
+}
+
+impl Decoder {
+    /// Decode the first frame of `data` into `out`, without JPEG reconstruction.
+    pub(super) fn decode_into_inner(&self, data: &[u8], out: &Py<PyAny>) -> PyResult<ImageInfo> {
+        match unsafe { JxlSignatureCheck(data.as_ptr(), data.len()) } {
+            JxlSignature::Codestream | JxlSignature::Container => {}
+            _ => return Err(to_pyjxlerror(DecodeError::InvalidInput)),
+        }
+        let boxes = self.metadata_boxes(data)?;
+        let parallel_runner = self.parallel_runner()?;
+        let dec = RawDecoder(unsafe { JxlDecoderCreate(null()) });
+        if dec.0.is_null() {
+            return Err(to_pyjxlerror(DecodeError::CannotCreateDecoder));
+        }
+        unsafe {
+            check(JxlDecoderSetParallelRunner(
+                dec.0,
+                parallel_runner.runner(),
+                parallel_runner.as_opaque_ptr(),
+            ))?;
+            check(JxlDecoderSubscribeEvents(
+                dec.0,
+                JxlDecoderStatus::BasicInfo as i32
+                    | JxlDecoderStatus::ColorEncoding as i32
+                    | JxlDecoderStatus::FullImage as i32,
+            ))?;
+            check(JxlDecoderSetInput(dec.0, data.as_ptr(), data.len()))?;
+            JxlDecoderCloseInput(dec.0);
+        }
+
+        let mut image = None;
+        // Kept alive, and so pinned by Python, until libjxl has finished writing
+        let mut buffer = None;
+        // Samples above 8 bits, decoded here and then quantized into `buffer`.
+        // Taken from the decoder, so concurrent calls allocate their own.
+        let mut scratch = self
+            .scratch
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        let mut wide = None;
+        loop {
+            match unsafe { JxlDecoderProcessInput(dec.0) } {
+                JxlDecoderStatus::BasicInfo => {
+                    let info = unsafe {
+                        let mut info = MaybeUninit::uninit();
+                        check(JxlDecoderGetBasicInfo(dec.0, info.as_mut_ptr()))?;
+                        info.assume_init()
+                    };
+                    parallel_runner.callback_basic_info(&info);
+                    let (mode, pixel_format, wide_type) = output_format(&info)?;
+                    let image_info = ImageInfo {
+                        mode: mode.to_string(),
+                        width: info.xsize,
+                        height: info.ysize,
+                        num_channels: info.num_color_channels,
+                        has_alpha_channel: info.alpha_bits > 0,
+                        exif: None,
+                        xmp: None,
+                        jumbf: None,
+                        level: level::DEFAULT_LEVEL,
+                        exceeds_level5: false,
+                    };
+                    image = Some((
+                        image_info.with_metadata(&boxes, self.strict)?,
+                        info.bits_per_sample,
+                        pixel_format,
+                        wide_type,
+                    ));
+                }
+                JxlDecoderStatus::ColorEncoding => {
+                    let Some((info, bits_per_sample, ..)) = &mut image else {
+                        return Err(to_pyjxlerror(DecodeError::InternalError(
+                            "color encoding before basic info",
+                        )));
+                    };
+                    // The same profile `__call__` reports the size of
+                    let mut icc_size = 0;
+                    check(unsafe {
+                        JxlDecoderGetICCProfileSize(
+                            dec.0,
+                            JxlColorProfileTarget::Data,
+                            &mut icc_size,
+                        )
+                    })?;
+                    info.exceeds_level5 = level::level5_violation(
+                        info.width,
+                        info.height,
+                        *bits_per_sample,
+                        icc_size,
+                    )
+                    .is_some();
+                }
+                JxlDecoderStatus::NeedImageOutBuffer => {
+                    let Some((_, _, pixel_format, wide_type)) = &image else {
+                        return Err(to_pyjxlerror(DecodeError::InternalError(
+                            "image buffer requested before basic info",
+                        )));
+                    };
+                    let mut size = 0;
+                    check(unsafe { JxlDecoderImageOutBufferSize(dec.0, pixel_format, &mut size) })?;
+                    let out = Python::attach(|py| {
+                        out_buffer(out.bind(py), pixel_format.data_type, size)
+                    })?;
+                    match wide_type {
+                        None => check(unsafe {
+                            JxlDecoderSetImageOutBuffer(dec.0, pixel_format, out.buf_ptr(), size)
+                        })?,
+                        Some(data_type) => {
+                            let wide_format = JxlPixelFormat {
+                                data_type: *data_type,
+                                ..*pixel_format
+                            };
+                            let mut wide_size = 0;
+                            check(unsafe {
+                                JxlDecoderImageOutBufferSize(dec.0, &wide_format, &mut wide_size)
+                            })?;
+                            let sample_size = match data_type {
+                                JxlDataType::Uint16 => 2,
+                                _ => 4,
+                            };
+                            let mut pixels =
+                                wide_pixels(scratch.take(), *data_type, wide_size / sample_size);
+                            let wide_ptr = match &mut pixels {
+                                Pixels::Uint16(samples) => samples.as_mut_ptr().cast(),
+                                Pixels::Float(samples) => samples.as_mut_ptr().cast(),
+                                _ => unreachable!(),
+                            };
+                            check(unsafe {
+                                JxlDecoderSetImageOutBuffer(
+                                    dec.0,
+                                    &wide_format,
+                                    wide_ptr,
+                                    wide_size,
+                                )
+                            })?;
+                            wide = Some(pixels);
+                        }
+                    }
+                    buffer = Some(out);
+                }
+                JxlDecoderStatus::FullImage | JxlDecoderStatus::Success => break,
+                _ => return Err(to_pyjxlerror(DecodeError::GenericError)),
+            }
+        }
+        let (Some((info, _, pixel_format, _)), Some(buffer)) = (image, buffer) else {
+            return Err(to_pyjxlerror(DecodeError::GenericError));
+        };
+        // The buffer is still pinned and libjxl is done writing to it
+        let pixels = unsafe {
+            std::slice::from_raw_parts_mut(buffer.buf_ptr().cast::<u8>(), buffer.len_bytes())
+        };
+        chunked::with_runner(&parallel_runner, || {
+            if let Some(wide) = wide {
+                self.quantizer.reduce_into(
+                    &wide,
+                    info.width as usize,
+                    pixel_format.num_channels as usize,
+                    pixels,
+                )?;
+                *self.scratch.lock().unwrap_or_else(PoisonError::into_inner) = Some(wide);
+            }
+            if !self.format.is_native() {
+                let sample_size = match pixel_format.data_type {
+                    JxlDataType::Uint16 | JxlDataType::Float16 => 2,
+                    JxlDataType::Float => 4,
+                    _ => 1,
+                };
+                self.format.rgb_to_buffer(
+                    pixels,
+                    sample_size,
+                    pixel_format.num_channels as usize,
+                    info.has_alpha_channel,
+                )?;
+            }
+            PyResult::Ok(())
+        })?;
+        Ok(info)
+    }
+}
@@ -0,0 +1,283 @@
+//! Reduction of decoded samples to 8 bits.
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+use half::f16;
+use half::slice::HalfFloatSliceExt;
+use jpegxl_rs::decode::Pixels;
+
+use crate::chunked;
+
+/// 8x8 Bayer matrix for ordered dithering
+const BAYER_8X8: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+/// Dithering applied when reducing samples to 8 bits
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub(crate) enum Dither {
+    /// Round to the nearest value
+    #[default]
+    None,
+    /// 8x8 Bayer matrix
+    Ordered,
+    /// Floyd-Steinberg error diffusion
+    ErrorDiffusion,
+}
+
+impl Dither {
+    pub(crate) fn from_name(name: &str) -> PyResult<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "none" => Ok(Dither::None),
+            "ordered" => Ok(Dither::Ordered),
+            "error-diffusion" | "floyd-steinberg" => Ok(Dither::ErrorDiffusion),
+            _ => Err(PyValueError::new_err(format!(
+                "Unknown dither {name:?}, expected none, ordered or error-diffusion"
+            ))),
+        }
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Dither::None => "none",
+            Dither::Ordered => "ordered",
+            Dither::ErrorDiffusion => "error-diffusion",
+        }
+    }
+}
+
+/// Conversion of decoded samples to 8 bits.
+///
+/// Samples are rounded to the nearest 8-bit value. Floating point samples
+/// outside `[0, 1]` are saturated when `clamp` is set and rejected otherwise.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Quantizer {
+    pub(crate) dither: Dither,
+    pub(crate) clamp: bool,
+}
+
+impl Default for Quantizer {
+    fn default() -> Self {
+        Self {
+            dither: Dither::None,
+            clamp: true,
+        }
+    }
+}
+
+impl Quantizer {
+    /// Convert interleaved pixels, `width` pixels of `num_channels` samples per row
+    pub(crate) fn reduce(
+        &self,
+        pixels: Pixels,
+        width: usize,
+        num_channels: usize,
+    ) -> PyResult<Vec<u8>> {
+        if let Pixels::Uint8(pixels) = pixels {
+            return Ok(pixels);
+        }
+        let mut out = vec![0; sample_count(&pixels)];
+        self.reduce_into(&pixels, width, num_channels, &mut out)?;
+        Ok(out)
+    }
+
+    /// Like `reduce`, writing the 8-bit samples into `out`
+    pub(crate) fn reduce_into(
+        &self,
+        pixels: &Pixels,
+        width: usize,
+        num_channels: usize,
+        out: &mut [u8],
+    ) -> PyResult<()> {
+        if out.len() != sample_count(pixels) {
+            return Err(PyValueError::new_err(format!(
+                "Cannot reduce {} samples into {} bytes",
+                sample_count(pixels),
+                out.len()
+            )));
+        }
+        let row_len = width * num_channels;
+        match pixels {
+            Pixels::Uint8(pixels) => out.copy_from_slice(pixels),
+            // Exactly round(v / 257), in integer arithmetic
+            Pixels::Uint16(pixels) if self.dither == Dither::None => {
+                map_samples(pixels, out, row_len, |v| {
+                    ((u32::from(v) * 255 + 32895) >> 16) as u8
+                })?
+            }
+            Pixels::Uint16(pixels) => {
+                self.quantize(pixels, out, row_len, num_channels, |v| f32::from(v) / 257.0)?
+            }
+            Pixels::Float(pixels) => {
+                if !self.clamp {
+                    check_range(pixels.iter().map(|&v| (0.0..=1.0).contains(&v)))?;
+                }
+                self.quantize(pixels, out, row_len, num_channels, |v| v * 255.0)?
+            }
+            Pixels::Float16(pixels) => {
+                if !self.clamp {
+                    check_range(pixels.iter().map(|&v| v >= f16::ZERO && v <= f16::ONE))?;
+                }
+                self.quantize_f16(pixels, out, row_len, num_channels)?
+            }
+        }
+        Ok(())
+    }
+
+    /// Quantize samples mapped onto a `0.0..=255.0` scale by `scale` into `out`
+    fn quantize<T: Copy + Sync>(
+        &self,
+        samples: &[T],
+        out: &mut [u8],
+        row_len: usize,
+        num_channels: usize,
+        scale: impl Fn(T) -> f32 + Sync,
+    ) -> PyResult<()> {
+        if self.dither == Dither::ErrorDiffusion {
+            // Each row depends on the one above, so this stays serial
+            diffuse_errors(samples, out, row_len, num_channels, scale);
+            return Ok(());
+        }
+        chunked::zip_rows(samples, out, row_len, |first_row, input, output| {
+            self.quantize_rows(input, output, first_row, row_len, num_channels, &scale)
+        })
+    }
+
+    /// Half floats are widened a chunk at a time, using F16C where available
+    fn quantize_f16(
+        &self,
+        samples: &[f16],
+        out: &mut [u8],
+        row_len: usize,
+        num_channels: usize,
+    ) -> PyResult<()> {
+        if self.dither == Dither::ErrorDiffusion {
+            let mut widened = vec![0.0; samples.len()];
+            samples.convert_to_f32_slice(&mut widened);
+            diffuse_errors(&widened, out, row_len, num_channels, |v| v * 255.0);
+            return Ok(());
+        }
+        chunked::zip_rows(samples, out, row_len, |first_row, input, output| {
+            let mut widened = vec![0.0; input.len()];
+            input.convert_to_f32_slice(&mut widened);
+            self.quantize_rows(&widened, output, first_row, row_len, num_channels, |v| {
+                v * 255.0
+            })
+        })
+    }
+
+    /// Round or ordered-dither whole rows starting at image row `first_row`
+    fn quantize_rows<T: Copy>(
+        &self,
+        input: &[T],
+        output: &mut [u8],
+        first_row: usize,
+        row_len: usize,
+        num_channels: usize,
+        scale: impl Fn(T) -> f32,
+    ) {
+        if self.dither == Dither::None {
+            for (out, &v) in output.iter_mut().zip(input) {
+                *out = round_u8(scale(v));
+            }
+            return;
+        }
+        let row_len = row_len.max(1);
+        for (y, (input, output)) in input
+            .chunks(row_len)
+            .zip(output.chunks_mut(row_len))
+            .enumerate()
+        {
+            let bayer = &BAYER_8X8[(first_row + y) % 8];
+            for (x, (input, output)) in input
+                .chunks(num_channels)
+                .zip(output.chunks_mut(num_channels))
+                .enumerate()
+            {
+                let threshold = (f32::from(bayer[x % 8]) + 0.5) / 64.0 - 0.5;
+                for (out, &v) in output.iter_mut().zip(input) {
+                    *out = round_u8(scale(v) + threshold);
+                }
+            }
+        }
+    }
+}
+
+/// Fail on the first sample outside `[0, 1]`, given whether each one is in range
+fn check_range(in_range: impl Iterator<Item = bool>) -> PyResult<()> {
+    match in_range.enumerate().find(|&(_, ok)| !ok) {
+        Some((pos, _)) => Err(PyValueError::new_err(format!(
+            "Sample {pos} is outside the range [0, 1]"
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn sample_count(pixels: &Pixels) -> usize {
+    match pixels {
+        Pixels::Uint8(samples) => samples.len(),
+        Pixels::Uint16(samples) => samples.len(),
+        Pixels::Float(samples) => samples.len(),
+        Pixels::Float16(samples) => samples.len(),
+    }
+}
+
+/// Apply `f` to every sample into `out`, in parallel for large images
+fn map_samples<T: Copy + Sync>(
+    samples: &[T],
+    out: &mut [u8],
+    row_len: usize,
+    f: impl Fn(T) -> u8 + Sync,
+) -> PyResult<()> {
+    chunked::zip_rows(samples, out, row_len, |_, input, output| {
+        for (out, &v) in output.iter_mut().zip(input) {
+            *out = f(v);
+        }
+    })
+}
+
+/// Floyd-Steinberg error diffusion of samples mapped onto `0.0..=255.0` by
+/// `scale` into `out`
+fn diffuse_errors<T: Copy>(
+    samples: &[T],
+    out: &mut [u8],
+    row_len: usize,
+    num_channels: usize,
+    scale: impl Fn(T) -> f32,
+) {
+    let row_len = row_len.max(1);
+    // Errors carried into the current and next row, padded by a pixel on
+    // either side
+    let mut errors = vec![0.0f32; row_len + 2 * num_channels];
+    let mut next = errors.clone();
+    for (row, out) in samples.chunks(row_len).zip(out.chunks_mut(row_len)) {
+        next.fill(0.0);
+        for (i, (&v, out)) in row.iter().zip(out).enumerate() {
+            let value = scale(v) + errors[i + num_channels];
+            let quantized = round_u8(value);
+            *out = quantized;
+            let error = match value.is_nan() {
+                true => 0.0,
+                false => value.clamp(0.0, 255.0) - f32::from(quantized),
+            };
+            errors[i + 2 * num_channels] += error * 7.0 / 16.0;
+            next[i] += error * 3.0 / 16.0;
+            next[i + num_channels] += error * 5.0 / 16.0;
+            next[i + 2 * num_channels] += error / 16.0;
+        }
+        std::mem::swap(&mut errors, &mut next);
+    }
+}
+
+/// Round to the nearest 8-bit value, saturating; NaN maps to 0
+fn round_u8(value: f32) -> u8 {
+    value.round().clamp(0.0, 255.0) as u8
+}
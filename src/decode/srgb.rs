@@ -0,0 +1,177 @@
+//! Decoding to float samples in sRGB primaries for the quality metrics.
+
+use std::mem::MaybeUninit;
+use std::ptr::null;
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+use jpegxl_rs::parallel::threads_runner::ThreadsRunner;
+use jpegxl_rs::parallel::ParallelRunner;
+use jpegxl_rs::DecodeError;
+use jpegxl_sys::color::color_encoding::{
+    JxlColorEncoding, JxlColorSpace, JxlPrimaries, JxlTransferFunction, JxlWhitePoint,
+};
+use jpegxl_sys::common::types::{JxlBool, JxlDataType, JxlEndianness, JxlPixelFormat};
+use jpegxl_sys::decode::{
+    JxlColorProfileTarget, JxlDecoderCloseInput, JxlDecoderCreate, JxlDecoderGetBasicInfo,
+    JxlDecoderGetColorAsEncodedProfile, JxlDecoderImageOutBufferSize, JxlDecoderProcessInput,
+    JxlDecoderSetImageOutBuffer, JxlDecoderSetInput, JxlDecoderSetParallelRunner,
+    JxlDecoderSetPreferredColorProfile, JxlDecoderStatus, JxlDecoderSubscribeEvents,
+};
+use jpegxl_sys::encoder::encode::JxlColorEncodingSetToLinearSRGB;
+
+use super::out_buffer::{check, RawDecoder};
+use super::{to_pyjxlerror, Decoder};
+
+/// Interleaved float samples of a decoded image, color channels then alpha
+pub(crate) struct FloatImage {
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) num_channels: u32,
+    /// Samples are sRGB encoded rather than linear
+    pub(crate) srgb_transfer: bool,
+    pub(crate) samples: Vec<f32>,
+}
+
+/// Whether samples in `encoding` only differ from sRGB in their transfer function
+fn has_srgb_gamut(encoding: &JxlColorEncoding) -> bool {
+    encoding.white_point == JxlWhitePoint::D65
+        && match encoding.color_space {
+            JxlColorSpace::Gray => true,
+            JxlColorSpace::Rgb => encoding.primaries == JxlPrimaries::SRgb,
+            _ => false,
+        }
+}
+
+impl Decoder {
+    /// Decode the first frame of `data` to float samples in sRGB primaries,
+    /// without reconstructing JPEG or quantizing to 8 bits.
+    ///
+    /// libjxl converts XYB encoded images to linear sRGB by itself. There is no
+    /// color management otherwise, so other images must be sRGB or linear sRGB.
+    pub(crate) fn decode_srgb_float(
+        &self,
+        data: &[u8],
+        parallel_runner: &ThreadsRunner,
+    ) -> PyResult<FloatImage> {
+        let dec = RawDecoder(unsafe { JxlDecoderCreate(null()) });
+        if dec.0.is_null() {
+            return Err(to_pyjxlerror(DecodeError::CannotCreateDecoder));
+        }
+        unsafe {
+            check(JxlDecoderSetParallelRunner(
+                dec.0,
+                parallel_runner.runner(),
+                parallel_runner.as_opaque_ptr(),
+            ))?;
+            check(JxlDecoderSubscribeEvents(
+                dec.0,
+                JxlDecoderStatus::BasicInfo as i32
+                    | JxlDecoderStatus::ColorEncoding as i32
+                    | JxlDecoderStatus::FullImage as i32,
+            ))?;
+            check(JxlDecoderSetInput(dec.0, data.as_ptr(), data.len()))?;
+            JxlDecoderCloseInput(dec.0);
+        }
+
+        let mut image = None;
+        loop {
+            match unsafe { JxlDecoderProcessInput(dec.0) } {
+                JxlDecoderStatus::BasicInfo => {
+                    let info = unsafe {
+                        let mut info = MaybeUninit::uninit();
+                        check(JxlDecoderGetBasicInfo(dec.0, info.as_mut_ptr()))?;
+                        info.assume_init()
+                    };
+                    parallel_runner.callback_basic_info(&info);
+                    // Whether the image is grayscale, and XYB encoded
+                    let color = (
+                        info.num_color_channels == 1,
+                        info.uses_original_profile == JxlBool::False,
+                    );
+                    image = Some((
+                        color,
+                        FloatImage {
+                            width: info.xsize,
+                            height: info.ysize,
+                            num_channels: info.num_color_channels + u32::from(info.alpha_bits > 0),
+                            srgb_transfer: false,
+                            samples: Vec::new(),
+                        },
+                    ));
+                }
+                JxlDecoderStatus::ColorEncoding => {
+                    let Some(((is_gray, is_xyb), image)) = &mut image else {
+                        return Err(to_pyjxlerror(DecodeError::InternalError(
+                            "color encoding before basic info",
+                        )));
+                    };
+                    if *is_xyb {
+                        let mut linear = MaybeUninit::uninit();
+                        unsafe {
+                            JxlColorEncodingSetToLinearSRGB(linear.as_mut_ptr(), (*is_gray).into());
+                            check(JxlDecoderSetPreferredColorProfile(dec.0, linear.as_ptr()))?;
+                        }
+                    }
+                    let mut encoding = MaybeUninit::uninit();
+                    // Fails when the image only has an ICC profile
+                    let encoding = match unsafe {
+                        JxlDecoderGetColorAsEncodedProfile(
+                            dec.0,
+                            JxlColorProfileTarget::Data,
+                            encoding.as_mut_ptr(),
+                        )
+                    } {
+                        JxlDecoderStatus::Success => Some(unsafe { encoding.assume_init() }),
+                        _ => None,
+                    };
+                    image.srgb_transfer = match encoding {
+                        Some(e) if has_srgb_gamut(&e) => match e.transfer_function {
+                            JxlTransferFunction::SRGB => true,
+                            JxlTransferFunction::Linear => false,
+                            _ => return Err(unsupported_profile()),
+                        },
+                        _ => return Err(unsupported_profile()),
+                    };
+                }
+                JxlDecoderStatus::NeedImageOutBuffer => {
+                    let Some((_, image)) = &mut image else {
+                        return Err(to_pyjxlerror(DecodeError::InternalError(
+                            "image buffer requested before basic info",
+                        )));
+                    };
+                    let pixel_format = JxlPixelFormat {
+                        num_channels: image.num_channels,
+                        data_type: JxlDataType::Float,
+                        endianness: JxlEndianness::Native,
+                        align: 0,
+                    };
+                    let mut size = 0;
+                    check(unsafe {
+                        JxlDecoderImageOutBufferSize(dec.0, &pixel_format, &mut size)
+                    })?;
+                    image.samples = vec![0.0; size / size_of::<f32>()];
+                    check(unsafe {
+                        JxlDecoderSetImageOutBuffer(
+                            dec.0,
+                            &pixel_format,
+                            image.samples.as_mut_ptr().cast(),
+                            size,
+                        )
+                    })?;
+                }
+                JxlDecoderStatus::FullImage | JxlDecoderStatus::Success => break,
+                _ => return Err(to_pyjxlerror(DecodeError::GenericError)),
+            }
+        }
+        match image {
+            Some((_, image)) if !image.samples.is_empty() => Ok(image),
+            _ => Err(to_pyjxlerror(DecodeError::GenericError)),
+        }
+    }
+}
+
+fn unsupported_profile() -> PyErr {
+    PyValueError::new_err("Only sRGB and linear sRGB images can be compared")
+}
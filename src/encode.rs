@@ -1,41 +1,242 @@
 use std::borrow::Cow;
+use std::path::PathBuf;
+use std::time::Instant;
 
 use pyo3::exceptions::{PyRuntimeError, PyValueError};
 use pyo3::prelude::*;
+use pyo3::pybacked::PyBackedBytes;
 
-use jpegxl_rs::encode::{ColorEncoding, EncoderFrame, EncoderSpeed, Metadata};
+use half::f16;
+
+use jpegxl_rs::decode::{Data, PixelFormat};
+use jpegxl_rs::encode::{ColorEncoding, EncoderFrame, EncoderSpeed, JxlEncoder, Metadata};
 use jpegxl_rs::parallel::threads_runner::ThreadsRunner;
-use jpegxl_rs::{encoder_builder, EncodeError};
+use jpegxl_rs::{decoder_builder, encoder_builder, DecodeError, EncodeError};
+use jpegxl_sys::common::types::JxlDataType;
 
-/// Represents different pixel types for encoding
-#[derive(Clone, Copy)]
-enum PixelType {
-    /// 8-bit unsigned integer (RGB, RGBA, L, LA modes)
-    Uint8 { num_channels: u32, has_alpha: bool },
-    /// 16-bit unsigned integer (I;16 mode, grayscale only)
+use crate::array::ArrayImage;
+use crate::batch;
+use crate::chunked;
+use crate::container;
+use crate::decode::Decoder;
+use crate::exif;
+use crate::format::{BufferFormat, Endianness};
+use crate::level;
+use crate::logging::{self, Level};
+use crate::metrics::{ssimulacra2, LinearImage};
+use crate::runner::{PooledRunner, RunnerPool};
+use crate::JxlVerificationError;
+
+mod stream;
+
+use stream::{FileSink, OutputSink, PixelSource, PyFileSink};
+
+/// Sample data type of the pixels handed to the encoder
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum SampleType {
+    Uint8,
     Uint16,
-    /// 32-bit float (F mode, grayscale only)
+    Float16,
     Float32,
 }
 
+impl SampleType {
+    /// Parse a buffer protocol format string in native byte order
+    pub(crate) fn from_format(format: &str) -> Option<Self> {
+        let format = format.trim_start_matches(['@', '=']);
+        #[cfg(target_endian = "little")]
+        let format = format.trim_start_matches('<');
+        #[cfg(target_endian = "big")]
+        let format = format.trim_start_matches(['>', '!']);
+        match format {
+            "B" => Some(SampleType::Uint8),
+            "H" => Some(SampleType::Uint16),
+            "e" => Some(SampleType::Float16),
+            "f" => Some(SampleType::Float32),
+            _ => None,
+        }
+    }
+
+    fn data_type(self) -> JxlDataType {
+        match self {
+            SampleType::Uint8 => JxlDataType::Uint8,
+            SampleType::Uint16 => JxlDataType::Uint16,
+            SampleType::Float16 => JxlDataType::Float16,
+            SampleType::Float32 => JxlDataType::Float,
+        }
+    }
+
+    /// Bits per sample and exponent bits per sample
+    fn bits_per_sample(self) -> (u32, u32) {
+        match self {
+            SampleType::Uint8 => (8, 0),
+            SampleType::Uint16 => (16, 0),
+            SampleType::Float16 => (16, 5),
+            SampleType::Float32 => (32, 8),
+        }
+    }
+
+    pub(crate) fn size(self) -> usize {
+        (self.bits_per_sample().0 / 8) as usize
+    }
+}
+
+/// Layout of the pixels handed to the encoder
+#[derive(Clone, Copy)]
+pub(crate) struct PixelType {
+    pub(crate) sample_type: SampleType,
+    /// Number of interleaved channels, including alpha
+    pub(crate) num_channels: u32,
+    pub(crate) has_alpha: bool,
+    /// Samples are linear light rather than sRGB encoded
+    pub(crate) linear: bool,
+}
+
 impl PixelType {
+    pub(crate) fn from_mode(mode: &str) -> PyResult<Self> {
+        let (sample_type, num_channels, has_alpha, linear) = match mode {
+            "RGBA" => (SampleType::Uint8, 4, true, false),
+            "RGB" => (SampleType::Uint8, 3, false, false),
+            "LA" => (SampleType::Uint8, 2, true, false),
+            "L" => (SampleType::Uint8, 1, false, false),
+            // I;16 and F are grayscale only
+            "I;16" => (SampleType::Uint16, 1, false, true),
+            "F" => (SampleType::Float32, 1, false, true),
+            _ => {
+                return Err(PyValueError::new_err(
+                    "Only RGB, RGBA, L, LA, I;16, F are supported.",
+                ))
+            }
+        };
+        Ok(Self {
+            sample_type,
+            num_channels,
+            has_alpha,
+            linear,
+        })
+    }
+
     fn color_encoding(&self) -> ColorEncoding {
-        match self {
-            PixelType::Uint8 { num_channels, .. } => match num_channels {
-                1 | 2 => ColorEncoding::SrgbLuma,
-                3 | 4 => ColorEncoding::Srgb,
-                _ => unreachable!("Invalid number of channels for Uint8 pixel type"),
-            },
-            PixelType::Uint16 | PixelType::Float32 => ColorEncoding::LinearSrgbLuma,
+        let grayscale = self.num_channels - u32::from(self.has_alpha) == 1;
+        match (grayscale, self.linear) {
+            (true, false) => ColorEncoding::SrgbLuma,
+            (true, true) => ColorEncoding::LinearSrgbLuma,
+            (false, false) => ColorEncoding::Srgb,
+            (false, true) => ColorEncoding::LinearSrgb,
         }
     }
 
     fn has_alpha(&self) -> bool {
-        match self {
-            PixelType::Uint8 { has_alpha, .. } => *has_alpha,
-            PixelType::Uint16 | PixelType::Float32 => false,
+        self.has_alpha
+    }
+
+    /// Number of interleaved channels, including alpha
+    fn num_channels(&self) -> u32 {
+        self.num_channels
+    }
+
+    fn data_type(&self) -> JxlDataType {
+        self.sample_type.data_type()
+    }
+
+    /// Bits per sample and exponent bits per sample
+    fn bits_per_sample(&self) -> (u32, u32) {
+        self.sample_type.bits_per_sample()
+    }
+
+    fn bytes_per_pixel(&self) -> usize {
+        self.sample_type.size() * self.num_channels as usize
+    }
+}
+
+/// Reinterpret raw bytes as samples, copying only if `data` is misaligned
+fn cast_samples<'a, T: bytemuck::Pod>(data: &'a [u8], mode: &str) -> PyResult<Cow<'a, [T]>> {
+    match bytemuck::try_cast_slice(data) {
+        Ok(samples) => Ok(Cow::Borrowed(samples)),
+        Err(bytemuck::PodCastError::TargetAlignmentGreaterAndInputNotAligned)
+            if data.len().is_multiple_of(std::mem::size_of::<T>()) =>
+        {
+            let mut samples = vec![T::zeroed(); data.len() / std::mem::size_of::<T>()];
+            bytemuck::cast_slice_mut(&mut samples).copy_from_slice(data);
+            Ok(Cow::Owned(samples))
         }
+        Err(e) => Err(PyValueError::new_err(format!(
+            "Failed to cast {mode} data to {} slice: {e}",
+            std::any::type_name::<T>().rsplit("::").next().unwrap()
+        ))),
+    }
+}
+
+/// Validate a row stride of `stride` bytes for `data` and return it as the
+/// libjxl pixel format alignment, which is 0 for tightly packed rows.
+///
+/// Rows are `stride` bytes apart; the last row does not need to be padded.
+fn row_align(
+    pixel_type: PixelType,
+    data: &[u8],
+    width: u32,
+    height: u32,
+    stride: usize,
+) -> PyResult<usize> {
+    let row_bytes = width as usize * pixel_type.bytes_per_pixel();
+    if stride < row_bytes {
+        return Err(PyValueError::new_err(format!(
+            "Row stride {stride} is smaller than a row of {row_bytes} bytes"
+        )));
+    }
+    if !stride.is_multiple_of(pixel_type.sample_type.size()) {
+        return Err(PyValueError::new_err(format!(
+            "Row stride {stride} is not a multiple of the sample size {}",
+            pixel_type.sample_type.size()
+        )));
+    }
+    let expected = (height as usize).saturating_sub(1) * stride + row_bytes;
+    if data.len() < expected {
+        return Err(PyValueError::new_err(format!(
+            "Expected at least {expected} bytes of pixel data for a {width}x{height} image with row stride {stride}, got {}",
+            data.len()
+        )));
+    }
+    // libjxl rounds each row up to a multiple of `align`, which is exactly
+    // `stride` for any stride at least one row long
+    Ok(if stride == row_bytes { 0 } else { stride })
+}
+
+/// Drop the padding between rows aligned as by [`row_align`], if there is any.
+fn pack_rows(data: &[u8], align: usize, row_bytes: usize, height: usize) -> Cow<'_, [u8]> {
+    if align == 0 {
+        return Cow::Borrowed(data);
     }
+    Cow::Owned(
+        (0..height)
+            .flat_map(|row| &data[row * align..row * align + row_bytes])
+            .copied()
+            .collect(),
+    )
+}
+
+/// Convert pixels in `format` to the packed native RGB(A) samples libjxl
+/// expects, returning them with their libjxl row alignment.
+fn native_pixels(
+    format: BufferFormat,
+    pixel_type: PixelType,
+    data: &[u8],
+    width: u32,
+    height: u32,
+    align: usize,
+) -> PyResult<(Cow<'_, [u8]>, usize)> {
+    if format.is_native() {
+        return Ok((Cow::Borrowed(data), align));
+    }
+    let row_bytes = width as usize * pixel_type.bytes_per_pixel();
+    let mut pixels = pack_rows(data, align, row_bytes, height as usize).into_owned();
+    format.buffer_to_rgb(
+        &mut pixels,
+        pixel_type.sample_type.size(),
+        pixel_type.num_channels() as usize,
+        pixel_type.has_alpha(),
+    )?;
+    Ok((Cow::Owned(pixels), 0))
 }
 
 #[pyclass(module = "pillow_jxl")]
@@ -47,14 +248,17 @@ pub struct Encoder {
     effort: u32,
     use_container: bool,
     use_original_profile: bool,
-    num_threads: isize,
+    runners: RunnerPool,
+    verify: bool,
+    format: BufferFormat,
+    level: Option<u8>,
 }
 
 #[allow(clippy::too_many_arguments)]
 #[pymethods]
 impl Encoder {
     #[new]
-    #[pyo3(signature = (mode, lossless=false, quality=1.0, decoding_speed=0, effort=7, use_container=false, use_original_profile=false, num_threads=-1))]
+    #[pyo3(signature = (mode, lossless=false, quality=1.0, decoding_speed=0, effort=7, use_container=false, use_original_profile=false, num_threads=-1, verify=false, channel_order="RGB", endianness="native", level=None))]
     fn new(
         mode: &str,
         lossless: bool,
@@ -64,32 +268,14 @@ impl Encoder {
         use_container: bool,
         use_original_profile: bool,
         num_threads: isize,
+        verify: bool,
+        channel_order: &str,
+        endianness: &str,
+        level: Option<u8>,
     ) -> PyResult<Self> {
-        let pixel_type = match mode {
-            "RGBA" => PixelType::Uint8 {
-                num_channels: 4,
-                has_alpha: true,
-            },
-            "RGB" => PixelType::Uint8 {
-                num_channels: 3,
-                has_alpha: false,
-            },
-            "LA" => PixelType::Uint8 {
-                num_channels: 2,
-                has_alpha: true,
-            },
-            "L" => PixelType::Uint8 {
-                num_channels: 1,
-                has_alpha: false,
-            },
-            "I;16" => PixelType::Uint16,
-            "F" => PixelType::Float32,
-            _ => {
-                return Err(PyValueError::new_err(
-                    "Only RGB, RGBA, L, LA, I;16, F are supported.",
-                ))
-            }
-        };
+        let pixel_type = PixelType::from_mode(mode)?;
+        let format = BufferFormat::new(channel_order, endianness)?;
+        let level = level.map(level::validate).transpose()?;
 
         let decoding_speed = match decoding_speed {
             0..=4 => decoding_speed,
@@ -113,12 +299,15 @@ impl Encoder {
             effort,
             use_container,
             use_original_profile,
-            num_threads,
+            runners: RunnerPool::new(num_threads),
+            verify,
+            format,
+            level,
         })
     }
 
     #[allow(clippy::too_many_arguments)]
-    #[pyo3(signature = (data, width, height, jpeg_encode, exif=None, jumb=None, xmp=None, compress=false))]
+    #[pyo3(signature = (data, width, height, jpeg_encode, exif=None, jumb=None, xmp=None, compress=false, stride=None))]
     fn __call__(
         &self,
         py: Python,
@@ -130,22 +319,163 @@ impl Encoder {
         jumb: Option<&[u8]>,
         xmp: Option<&[u8]>,
         compress: bool,
+        stride: Option<usize>,
     ) -> PyResult<Cow<'_, [u8]>> {
-        py.detach(|| self.call_inner(data, width, height, jpeg_encode, exif, jumb, xmp, compress))
+        logging::detach(py, || {
+            self.call_inner(
+                data,
+                width,
+                height,
+                jpeg_encode,
+                exif,
+                jumb,
+                xmp,
+                compress,
+                stride,
+            )
+        })
     }
 
-    fn __repr__(&self) -> PyResult<String> {
-        Ok(format!(
-            "Encoder(has_alpha={}, lossless={}, quality={}, decoding_speed={}, effort={}, num_threads={})",
-            self.pixel_type.has_alpha(), self.lossless, self.quality, self.decoding_speed, self.effort, self.num_threads
-        ))
+    #[pyo3(signature = (array, exif=None, jumb=None, xmp=None, compress=false))]
+    fn encode_array(
+        &self,
+        py: Python,
+        array: &Bound<'_, PyAny>,
+        exif: Option<&[u8]>,
+        jumb: Option<&[u8]>,
+        xmp: Option<&[u8]>,
+        compress: bool,
+    ) -> PyResult<Cow<'_, [u8]>> {
+        let image = ArrayImage::from_buffer(array)?;
+        logging::detach(py, || {
+            let parallel_runner = self.parallel_runner()?;
+            let (pixels, stride) = image.pixels();
+            // Arrays carry their own byte order, which must be native
+            let format = BufferFormat {
+                endianness: Endianness::Native,
+                ..self.format
+            };
+            self.encode_with(
+                &parallel_runner,
+                format,
+                image.pixel_type,
+                &pixels,
+                image.width,
+                image.height,
+                stride,
+                false,
+                exif,
+                jumb,
+                xmp,
+                compress,
+            )
+            .map(Cow::Owned)
+        })
     }
-}
 
-impl Encoder {
     #[allow(clippy::too_many_arguments)]
-    fn call_inner(
+    #[pyo3(signature = (images, jpeg_encode=false, max_workers=None, exif=None, jumb=None, xmp=None, compress=false, stride=None))]
+    fn encode_many<'py>(
+        &self,
+        py: Python<'py>,
+        images: Vec<(PyBackedBytes, u32, u32)>,
+        jpeg_encode: bool,
+        max_workers: Option<usize>,
+        exif: Option<&[u8]>,
+        jumb: Option<&[u8]>,
+        xmp: Option<&[u8]>,
+        compress: bool,
+        stride: Option<usize>,
+    ) -> PyResult<Vec<Bound<'py, PyAny>>> {
+        let results = logging::detach(py, || {
+            batch::run(&images, max_workers, |runner, (data, width, height)| {
+                self.encode_with(
+                    runner,
+                    self.format,
+                    self.pixel_type,
+                    data,
+                    *width,
+                    *height,
+                    stride,
+                    jpeg_encode,
+                    exif,
+                    jumb,
+                    xmp,
+                    compress,
+                )
+                .map(Cow::<[u8]>::Owned)
+            })
+        })?;
+        batch::into_pylist(py, results)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (data, width, height, target_size=None, target_score=None, max_iterations=8, exif=None, jumb=None, xmp=None, compress=false))]
+    fn encode_target(
+        &self,
+        py: Python,
+        data: &[u8],
+        width: u32,
+        height: u32,
+        target_size: Option<usize>,
+        target_score: Option<f64>,
+        max_iterations: u32,
+        exif: Option<&[u8]>,
+        jumb: Option<&[u8]>,
+        xmp: Option<&[u8]>,
+        compress: bool,
+    ) -> PyResult<EncodeTargetResult<'_>> {
+        let target = match (target_size, target_score) {
+            (Some(size), None) => RateTarget::Size(size),
+            (None, Some(score)) => RateTarget::Score(score),
+            _ => {
+                return Err(PyValueError::new_err(
+                    "Exactly one of target_size and target_score must be given",
+                ))
+            }
+        };
+        logging::detach(py, || {
+            self.encode_target_inner(
+                data,
+                width,
+                height,
+                target,
+                max_iterations,
+                exif,
+                jumb,
+                xmp,
+                compress,
+            )
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (source, fp, width, height, exif=None, jumb=None, xmp=None, compress=false))]
+    fn encode_stream(
         &self,
+        py: Python,
+        source: &Bound<'_, PyAny>,
+        fp: Py<PyAny>,
+        width: u32,
+        height: u32,
+        exif: Option<&[u8]>,
+        jumb: Option<&[u8]>,
+        xmp: Option<&[u8]>,
+        compress: bool,
+    ) -> PyResult<u64> {
+        let source = PixelSource::new(source)?;
+        let mut sink = PyFileSink::new(fp);
+        logging::detach(py, || {
+            self.encode_stream_inner(source, &mut sink, width, height, exif, jumb, xmp, compress)
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (fp, data, width, height, jpeg_encode, exif=None, jumb=None, xmp=None, compress=false, stride=None, streaming=false))]
+    fn encode_to(
+        &self,
+        py: Python,
+        fp: &Bound<'_, PyAny>,
         data: &[u8],
         width: u32,
         height: u32,
@@ -154,28 +484,129 @@ impl Encoder {
         jumb: Option<&[u8]>,
         xmp: Option<&[u8]>,
         compress: bool,
-    ) -> PyResult<Cow<'_, [u8]>> {
-        let parallel_runner = ThreadsRunner::new(
-            None,
-            if self.num_threads < 0 {
-                None
-            } else {
-                Some(self.num_threads as usize)
-            },
-        )
-        .ok_or_else(|| PyRuntimeError::new_err("Could not create JxlThreadsRunner"))?;
-        let mut encoder = encoder_builder()
-            .parallel_runner(&parallel_runner)
-            .jpeg_quality(self.quality)
-            .has_alpha(self.pixel_type.has_alpha())
+        stride: Option<usize>,
+        streaming: bool,
+    ) -> PyResult<u64> {
+        if let Ok(path) = fp.extract::<PathBuf>() {
+            return logging::detach(py, || {
+                let mut sink = FileSink::create(&path)?;
+                let result = self.encode_to_inner(
+                    &mut sink,
+                    data,
+                    width,
+                    height,
+                    jpeg_encode,
+                    exif,
+                    jumb,
+                    xmp,
+                    compress,
+                    stride,
+                    streaming,
+                );
+                let result = result.and_then(|written| sink.finish().map(|()| written));
+                // Don't leave a truncated file behind
+                if result.is_err() {
+                    let _ = std::fs::remove_file(&path);
+                }
+                result
+            });
+        }
+        let mut sink = PyFileSink::new(fp.clone().unbind());
+        logging::detach(py, || {
+            self.encode_to_inner(
+                &mut sink,
+                data,
+                width,
+                height,
+                jpeg_encode,
+                exif,
+                jumb,
+                xmp,
+                compress,
+                stride,
+                streaming,
+            )
+        })
+    }
+
+    /// Run the check `verify=True` makes of `encoded` against input `data`,
+    /// which tests use to exercise mismatches
+    #[pyo3(signature = (encoded, data, jpeg_encode=false))]
+    fn _verify(&self, py: Python, encoded: &[u8], data: &[u8], jpeg_encode: bool) -> PyResult<()> {
+        logging::detach(py, || {
+            let parallel_runner = self.parallel_runner()?;
+            match jpeg_encode {
+                true => Self::verify_jpeg(&parallel_runner, encoded, data),
+                false => Self::verify_pixels(&parallel_runner, self.pixel_type, encoded, data),
+            }
+        })
+    }
+
+    fn __repr__(&self) -> PyResult<String> {
+        Ok(format!(
+            "Encoder(has_alpha={}, lossless={}, quality={}, decoding_speed={}, effort={}, num_threads={}, verify={}, level={})",
+            self.pixel_type.has_alpha(), self.lossless, self.quality, self.decoding_speed, self.effort, self.runners.num_threads(), self.verify,
+            self.level.map_or("None".to_string(), |level| level.to_string())
+        ))
+    }
+}
+
+impl Encoder {
+    fn parallel_runner(&self) -> PyResult<PooledRunner<'_>> {
+        self.runners.get()
+    }
+
+    fn build_encoder<'prl>(
+        &self,
+        parallel_runner: &'prl ThreadsRunner,
+        pixel_type: PixelType,
+        distance: Option<f32>,
+    ) -> PyResult<JxlEncoder<'prl, 'static>> {
+        let builder = encoder_builder()
+            .parallel_runner(parallel_runner)
+            .has_alpha(pixel_type.has_alpha())
             .lossless(self.lossless)
             .use_container(self.use_container)
-            .decoding_speed(self.decoding_speed)
-            .build()
-            .map_err(to_pyjxlerror)?;
+            .decoding_speed(self.decoding_speed);
+        let mut encoder = match distance {
+            Some(distance) => builder.quality(distance).build(),
+            None => builder.jpeg_quality(self.quality).build(),
+        }
+        .map_err(to_pyjxlerror)?;
         encoder.uses_original_profile = self.use_original_profile;
-        encoder.color_encoding = Some(self.pixel_type.color_encoding());
-        encoder.speed = match self.effort {
+        encoder.color_encoding = Some(pixel_type.color_encoding());
+        encoder.speed = self.speed()?;
+        Ok(encoder)
+    }
+
+    /// Fail early for images that can't be encoded at the requested level 5
+    fn check_level(&self, width: u32, height: u32, pixel_type: PixelType) -> PyResult<()> {
+        if self.level != Some(5) {
+            return Ok(());
+        }
+        let (bits, _) = pixel_type.bits_per_sample();
+        match level::level5_violation(width, height, bits, 0) {
+            Some(violation) => Err(PyValueError::new_err(format!(
+                "Cannot encode at level 5: {violation}"
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Declare the requested level in an encoded file. libjxl already picks
+    /// level 10 when the image needs it, marking it with a `jxll` box.
+    fn apply_level(&self, buffer: Vec<u8>) -> PyResult<Vec<u8>> {
+        match self.level {
+            Some(10) => container::declare_level(buffer, 10),
+            Some(level) if container::file_level(&buffer)? != level => Err(PyValueError::new_err(
+                format!("Image requires a codestream level above {level}"),
+            )),
+            _ => Ok(buffer),
+        }
+    }
+
+    fn speed(&self) -> PyResult<EncoderSpeed> {
+        Ok(match self.effort {
             1 => EncoderSpeed::Lightning,
             2 => EncoderSpeed::Thunder,
             3 => EncoderSpeed::Falcon,
@@ -187,64 +618,459 @@ impl Encoder {
             9 => EncoderSpeed::Tortoise,
             10 => EncoderSpeed::Glacier,
             _ => return Err(PyValueError::new_err("Invalid effort")),
-        };
+        })
+    }
 
-        // Add metadata if provided (for non-JPEG encode)
-        if !jpeg_encode {
-            if let Some(exif_data) = exif {
+    fn add_metadata(
+        encoder: &mut JxlEncoder,
+        exif: Option<&[u8]>,
+        jumb: Option<&[u8]>,
+        xmp: Option<&[u8]>,
+        compress: bool,
+    ) -> PyResult<()> {
+        if let Some(exif_data) = exif {
+            encoder
+                .add_metadata(&Metadata::Exif(&exif::to_box(exif_data)?), compress)
+                .map_err(to_pyjxlerror)?
+        }
+        if let Some(xmp_data) = xmp {
+            encoder
+                .add_metadata(&Metadata::Xmp(xmp_data), compress)
+                .map_err(to_pyjxlerror)?
+        }
+        if let Some(jumb_data) = jumb {
+            encoder
+                .add_metadata(&Metadata::Jumb(jumb_data), compress)
+                .map_err(to_pyjxlerror)?
+        }
+        Ok(())
+    }
+
+    fn encode_pixels(
+        encoder: &mut JxlEncoder,
+        pixel_type: PixelType,
+        data: &[u8],
+        width: u32,
+        height: u32,
+        align: usize,
+    ) -> PyResult<Vec<u8>> {
+        let num_channels = pixel_type.num_channels();
+        let buffer = match pixel_type.sample_type {
+            SampleType::Uint8 => {
+                let frame = EncoderFrame::new(data)
+                    .num_channels(num_channels)
+                    .align(align);
                 encoder
-                    .add_metadata(&Metadata::Exif(exif_data), compress)
+                    .encode_frame::<u8, u8>(&frame, width, height)
                     .map_err(to_pyjxlerror)?
+                    .data
             }
-            if let Some(xmp_data) = xmp {
+            SampleType::Uint16 => {
+                let data_u16 = cast_samples::<u16>(data, "I;16")?;
+                let frame = EncoderFrame::new(&data_u16)
+                    .num_channels(num_channels)
+                    .align(align);
                 encoder
-                    .add_metadata(&Metadata::Xmp(xmp_data), compress)
+                    .encode_frame::<u16, u16>(&frame, width, height)
                     .map_err(to_pyjxlerror)?
+                    .data
             }
-            if let Some(jumb_data) = jumb {
+            SampleType::Float16 => {
+                let data_f16 = cast_samples::<f16>(data, "float16")?;
+                let frame = EncoderFrame::new(&data_f16)
+                    .num_channels(num_channels)
+                    .align(align);
                 encoder
-                    .add_metadata(&Metadata::Jumb(jumb_data), compress)
+                    .encode_frame::<f16, f16>(&frame, width, height)
                     .map_err(to_pyjxlerror)?
+                    .data
             }
+            SampleType::Float32 => {
+                let data_f32 = cast_samples::<f32>(data, "F")?;
+                let frame = EncoderFrame::new(&data_f32)
+                    .num_channels(num_channels)
+                    .align(align);
+                encoder
+                    .encode_frame::<f32, f32>(&frame, width, height)
+                    .map_err(to_pyjxlerror)?
+                    .data
+            }
+        };
+        Ok(buffer)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn call_inner(
+        &self,
+        data: &[u8],
+        width: u32,
+        height: u32,
+        jpeg_encode: bool,
+        exif: Option<&[u8]>,
+        jumb: Option<&[u8]>,
+        xmp: Option<&[u8]>,
+        compress: bool,
+        stride: Option<usize>,
+    ) -> PyResult<Cow<'_, [u8]>> {
+        let parallel_runner = self.parallel_runner()?;
+        self.encode_with(
+            &parallel_runner,
+            self.format,
+            self.pixel_type,
+            data,
+            width,
+            height,
+            stride,
+            jpeg_encode,
+            exif,
+            jumb,
+            xmp,
+            compress,
+        )
+        .map(Cow::Owned)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn encode_with(
+        &self,
+        parallel_runner: &ThreadsRunner,
+        format: BufferFormat,
+        pixel_type: PixelType,
+        data: &[u8],
+        width: u32,
+        height: u32,
+        stride: Option<usize>,
+        jpeg_encode: bool,
+        exif: Option<&[u8]>,
+        jumb: Option<&[u8]>,
+        xmp: Option<&[u8]>,
+        compress: bool,
+    ) -> PyResult<Vec<u8>> {
+        if self.verify && !jpeg_encode && !self.lossless {
+            return Err(PyValueError::new_err(
+                "Verification requires lossless or JPEG reconstruction encoding",
+            ));
+        }
+        if jpeg_encode && stride.is_some() {
+            return Err(PyValueError::new_err(
+                "stride only applies to pixel data, not JPEG reconstruction",
+            ));
         }
+        let align = match stride {
+            Some(stride) => row_align(pixel_type, data, width, height, stride)?,
+            None => 0,
+        };
+        let (data, align) = match jpeg_encode {
+            true => (Cow::Borrowed(data), align),
+            false => chunked::with_runner(parallel_runner, || {
+                native_pixels(format, pixel_type, data, width, height, align)
+            })?,
+        };
+        let data = &*data;
+        self.check_level(width, height, pixel_type)?;
+        let start = Instant::now();
+        let mut encoder = self.build_encoder(parallel_runner, pixel_type, None)?;
 
         let buffer: Vec<u8> = if jpeg_encode {
             encoder.encode_jpeg(data).map_err(to_pyjxlerror)?.data
         } else {
-            match self.pixel_type {
-                PixelType::Uint8 { num_channels, .. } => {
-                    let frame = EncoderFrame::new(data).num_channels(num_channels);
-                    encoder
-                        .encode_frame::<u8, u8>(&frame, width, height)
-                        .map_err(to_pyjxlerror)?
-                        .data
+            // Add metadata if provided (for non-JPEG encode)
+            Self::add_metadata(&mut encoder, exif, jumb, xmp, compress)?;
+            Self::encode_pixels(&mut encoder, pixel_type, data, width, height, align)?
+        };
+        let buffer = self.apply_level(buffer)?;
+        if self.verify {
+            if jpeg_encode {
+                Self::verify_jpeg(parallel_runner, &buffer, data)?;
+            } else {
+                let row_bytes = width as usize * pixel_type.bytes_per_pixel();
+                let data = pack_rows(data, align, row_bytes, height as usize);
+                Self::verify_pixels(parallel_runner, pixel_type, &buffer, &data)?;
+            }
+        }
+        logging::log(Level::Debug, || {
+            let input = match jpeg_encode {
+                true => format!("{} byte JPEG", data.len()),
+                false => format!("{width}x{height} image"),
+            };
+            format!(
+                "Encoded {input} to {} bytes in {:.1} ms{}",
+                buffer.len(),
+                logging::millis(start.elapsed()),
+                if self.verify { ", verified" } else { "" }
+            )
+        });
+        Ok(buffer)
+    }
+}
+
+impl Encoder {
+    #[allow(clippy::too_many_arguments)]
+    fn encode_to_inner(
+        &self,
+        sink: &mut dyn OutputSink,
+        data: &[u8],
+        width: u32,
+        height: u32,
+        jpeg_encode: bool,
+        exif: Option<&[u8]>,
+        jumb: Option<&[u8]>,
+        xmp: Option<&[u8]>,
+        compress: bool,
+        stride: Option<usize>,
+        streaming: bool,
+    ) -> PyResult<u64> {
+        let row_bytes = width as usize * self.pixel_type.bytes_per_pixel();
+        let align = match stride {
+            // JPEG data is passed through, and rejected with a stride below
+            _ if jpeg_encode => 0,
+            Some(stride) => row_align(self.pixel_type, data, width, height, stride)?,
+            None => {
+                let expected = row_bytes * height as usize;
+                if data.len() != expected {
+                    return Err(PyValueError::new_err(format!(
+                        "Expected {expected} bytes of pixel data for a {width}x{height} image, got {}",
+                        data.len()
+                    )));
                 }
-                PixelType::Uint16 => {
-                    let data_u16: &[u16] = bytemuck::try_cast_slice(data).map_err(|e| {
-                        PyValueError::new_err(format!("Failed to cast I;16 data to u16 slice: {e}"))
-                    })?;
-                    let frame = EncoderFrame::new(data_u16).num_channels(1);
-                    encoder
-                        .encode_frame::<u16, u16>(&frame, width, height)
-                        .map_err(to_pyjxlerror)?
-                        .data
+                0
+            }
+        };
+        // The chunked encoder is opt-in; JPEG reconstruction and verification
+        // need the whole codestream at once anyway
+        if !streaming || jpeg_encode || self.verify {
+            let buffer = self.call_inner(
+                data,
+                width,
+                height,
+                jpeg_encode,
+                exif,
+                jumb,
+                xmp,
+                compress,
+                stride,
+            )?;
+            sink.write(&buffer)?;
+            return Ok(buffer.len() as u64);
+        }
+        self.encode_stream_inner(
+            PixelSource::Buffer(data, if align == 0 { row_bytes } else { align }),
+            sink,
+            width,
+            height,
+            exif,
+            jumb,
+            xmp,
+            compress,
+        )
+    }
+}
+
+/// Index of the first sample where two buffers differ, if any.
+fn first_mismatch<T: PartialEq>(decoded: &[T], original: &[T]) -> Option<usize> {
+    decoded
+        .iter()
+        .zip(original)
+        .position(|(a, b)| a != b)
+        .or_else(|| (decoded.len() != original.len()).then(|| decoded.len().min(original.len())))
+}
+
+impl Encoder {
+    /// Check that the JPEG reconstructed from `encoded` matches the input JPEG bytes.
+    fn verify_jpeg(parallel_runner: &ThreadsRunner, encoded: &[u8], jpeg: &[u8]) -> PyResult<()> {
+        let decoder = decoder_builder()
+            .parallel_runner(parallel_runner)
+            .build()
+            .map_err(to_pydecodeerror)?;
+        let (_, reconstructed) = decoder.reconstruct(encoded).map_err(to_pydecodeerror)?;
+        let Data::Jpeg(reconstructed) = reconstructed else {
+            return Err(JxlVerificationError::new_err(
+                "JPEG reconstruction data is missing from the encoded image",
+            ));
+        };
+        match first_mismatch(&reconstructed, jpeg) {
+            Some(pos) => Err(JxlVerificationError::new_err(format!(
+                "Reconstructed JPEG differs from the input at byte {pos}"
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Check that decoding `encoded` gives back exactly the input pixels.
+    fn verify_pixels(
+        parallel_runner: &ThreadsRunner,
+        pixel_type: PixelType,
+        encoded: &[u8],
+        data: &[u8],
+    ) -> PyResult<()> {
+        let decoder = decoder_builder()
+            .parallel_runner(parallel_runner)
+            .pixel_format(PixelFormat {
+                num_channels: pixel_type.num_channels(),
+                ..PixelFormat::default()
+            })
+            .build()
+            .map_err(to_pydecodeerror)?;
+        let decoded: Vec<u8> = match pixel_type.sample_type {
+            SampleType::Uint8 => {
+                decoder
+                    .decode_with::<u8>(encoded)
+                    .map_err(to_pydecodeerror)?
+                    .1
+            }
+            SampleType::Uint16 => bytemuck::cast_slice(
+                &decoder
+                    .decode_with::<u16>(encoded)
+                    .map_err(to_pydecodeerror)?
+                    .1,
+            )
+            .to_vec(),
+            SampleType::Float16 => bytemuck::cast_slice(
+                &decoder
+                    .decode_with::<f16>(encoded)
+                    .map_err(to_pydecodeerror)?
+                    .1,
+            )
+            .to_vec(),
+            SampleType::Float32 => bytemuck::cast_slice(
+                &decoder
+                    .decode_with::<f32>(encoded)
+                    .map_err(to_pydecodeerror)?
+                    .1,
+            )
+            .to_vec(),
+        };
+        match first_mismatch(&decoded, data).map(|pos| pos / pixel_type.sample_type.size()) {
+            Some(pos) => Err(JxlVerificationError::new_err(format!(
+                "Decoded pixels differ from the input at pixel {}, sample {pos}",
+                pos / pixel_type.num_channels() as usize
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Rate control target for [`Encoder::encode_target`].
+#[derive(Clone, Copy)]
+enum RateTarget {
+    /// Best quality whose encoded size does not exceed this many bytes.
+    Size(usize),
+    /// Smallest file whose SSIMULACRA2 score is at least this value.
+    Score(f64),
+}
+
+/// Butteraugli distance bounds searched by the rate control.
+const MIN_DISTANCE: f32 = 0.05;
+const MAX_DISTANCE: f32 = 25.0;
+
+impl Encoder {
+    #[allow(clippy::too_many_arguments)]
+    fn encode_target_inner(
+        &self,
+        data: &[u8],
+        width: u32,
+        height: u32,
+        target: RateTarget,
+        max_iterations: u32,
+        exif: Option<&[u8]>,
+        jumb: Option<&[u8]>,
+        xmp: Option<&[u8]>,
+        compress: bool,
+    ) -> PyResult<EncodeTargetResult<'_>> {
+        if self.lossless {
+            return Err(PyValueError::new_err(
+                "Rate control is only available for lossy encoding",
+            ));
+        }
+        let parallel_runner = self.parallel_runner()?;
+        let (data, _) = chunked::with_runner(&parallel_runner, || {
+            native_pixels(self.format, self.pixel_type, data, width, height, 0)
+        })?;
+        let data = &*data;
+        self.check_level(width, height, self.pixel_type)?;
+        let reference = match target {
+            RateTarget::Score(_) => Some(LinearImage::from_pixels(
+                self.pixel_type,
+                data,
+                width,
+                height,
+            )?),
+            RateTarget::Size(_) => None,
+        };
+        let decoder = Decoder::with_threads(self.runners.num_threads());
+
+        // Encode at the given distance and report whether the result meets the target.
+        let encode_at = |distance: f32| -> PyResult<(Vec<u8>, bool)> {
+            let mut encoder =
+                self.build_encoder(&parallel_runner, self.pixel_type, Some(distance))?;
+            Self::add_metadata(&mut encoder, exif, jumb, xmp, compress)?;
+            let buffer =
+                Self::encode_pixels(&mut encoder, self.pixel_type, data, width, height, 0)?;
+            let buffer = self.apply_level(buffer)?;
+            let accepted = match (target, &reference) {
+                (RateTarget::Size(max_size), _) => buffer.len() <= max_size,
+                (RateTarget::Score(min_score), Some(reference)) => {
+                    let distorted = LinearImage::from_float(
+                        decoder.decode_srgb_float(&buffer, &parallel_runner)?,
+                    )?;
+                    ssimulacra2(reference, &distorted)? >= min_score
                 }
-                PixelType::Float32 => {
-                    let data_f32: &[f32] = bytemuck::try_cast_slice(data).map_err(|e| {
-                        PyValueError::new_err(format!("Failed to cast F data to f32 slice: {e}"))
-                    })?;
-                    let frame = EncoderFrame::new(data_f32).num_channels(1);
-                    encoder
-                        .encode_frame::<f32, f32>(&frame, width, height)
-                        .map_err(to_pyjxlerror)?
-                        .data
+                (RateTarget::Score(_), None) => unreachable!(),
+            };
+            Ok((buffer, accepted))
+        };
+
+        // Smaller distances give larger, higher quality files. Search the largest
+        // acceptable distance for a score target and the smallest for a size target,
+        // bisecting in log space since file size is roughly log-linear in distance.
+        let (mut lo, mut hi) = (MIN_DISTANCE.ln(), MAX_DISTANCE.ln());
+        let bound = match target {
+            RateTarget::Size(_) => MAX_DISTANCE,
+            RateTarget::Score(_) => MIN_DISTANCE,
+        };
+        let (buffer, accepted) = encode_at(bound)?;
+        if !accepted {
+            return Err(PyValueError::new_err(match target {
+                RateTarget::Size(size) => format!(
+                    "Cannot reach target size of {size} bytes, smallest encode is {} bytes",
+                    buffer.len()
+                ),
+                RateTarget::Score(score) => {
+                    format!("Cannot reach target SSIMULACRA2 score of {score}")
                 }
+            }));
+        }
+        let mut best = (buffer, bound);
+
+        for _ in 0..max_iterations {
+            let distance = ((lo + hi) / 2.0).exp();
+            let (buffer, accepted) = encode_at(distance)?;
+            let tighten_low = match target {
+                RateTarget::Size(_) => !accepted,
+                RateTarget::Score(_) => accepted,
+            };
+            if accepted {
+                best = (buffer, distance);
             }
-        };
-        Ok(Cow::Owned(buffer))
+            if tighten_low {
+                lo = distance.ln();
+            } else {
+                hi = distance.ln();
+            }
+        }
+
+        let (buffer, distance) = best;
+        let size = buffer.len();
+        Ok((Cow::Owned(buffer), size, distance))
     }
 }
 
+type EncodeTargetResult<'a> = (Cow<'a, [u8]>, usize, f32);
+
 fn to_pyjxlerror(e: EncodeError) -> PyErr {
     PyRuntimeError::new_err(e.to_string())
 }
+
+fn to_pydecodeerror(e: DecodeError) -> PyErr {
+    PyRuntimeError::new_err(e.to_string())
+}
@@ -0,0 +1,723 @@
+//! Chunked encoding on top of libjxl's streaming APIs.
+//!
+//! jpegxl-rs only encodes frames that are fully resident in memory, so this
+//! module drives `JxlEncoderAddChunkedFrame` and `JxlEncoderSetOutputProcessor`
+//! directly. Pixels are pulled from Python on demand and the codestream is
+//! handed to an [`OutputSink`] as libjxl produces it.
+
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::mem::MaybeUninit;
+use std::path::Path;
+use std::ptr::{null, null_mut};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyIterator};
+
+use jpegxl_rs::parallel::threads_runner::ThreadsRunner;
+use jpegxl_rs::parallel::ParallelRunner;
+use jpegxl_sys::color::color_encoding::JxlColorEncoding;
+use jpegxl_sys::common::types::{JxlBoxType, JxlEndianness, JxlPixelFormat};
+use jpegxl_sys::encoder::encode::{
+    JxlChunkedFrameInputSource, JxlEncoder, JxlEncoderAddBox, JxlEncoderAddChunkedFrame,
+    JxlEncoderCloseBoxes, JxlEncoderCreate, JxlEncoderDestroy, JxlEncoderDistanceFromQuality,
+    JxlEncoderError, JxlEncoderFrameSettingId, JxlEncoderFrameSettings,
+    JxlEncoderFrameSettingsCreate, JxlEncoderFrameSettingsSetOption, JxlEncoderGetError,
+    JxlEncoderInitBasicInfo, JxlEncoderOutputProcessor, JxlEncoderSetBasicInfo,
+    JxlEncoderSetCodestreamLevel, JxlEncoderSetColorEncoding, JxlEncoderSetFrameDistance,
+    JxlEncoderSetFrameLossless, JxlEncoderSetOutputProcessor, JxlEncoderSetParallelRunner,
+    JxlEncoderStatus, JxlEncoderUseBoxes, JxlEncoderUseContainer,
+};
+
+use super::Encoder;
+use crate::exif;
+use crate::format::BufferFormat;
+
+/// Size of the output buffers handed to libjxl
+const OUTPUT_CHUNK_SIZE: usize = 1 << 16;
+
+// jpegxl-sys declares the callback structs with private fields and no
+// constructor, so they are built as mirrors and transmuted. Size and alignment
+// are checked below; the field order can't be, which is why jpegxl-sys is
+// pinned to an exact version in Cargo.toml.
+#[repr(C)]
+struct OutputProcessor {
+    opaque: *mut c_void,
+    get_buffer: extern "C-unwind" fn(opaque: *mut c_void, size: *mut usize) -> *mut c_void,
+    release_buffer: extern "C-unwind" fn(opaque: *mut c_void, written_bytes: usize),
+    seek: Option<extern "C-unwind" fn(opaque: *mut c_void, position: u64)>,
+    set_finalized_position: extern "C-unwind" fn(opaque: *mut c_void, finalized_position: u64),
+}
+
+#[repr(C)]
+struct ChunkedFrameInputSource {
+    opaque: *mut c_void,
+    get_color_channels_pixel_format:
+        extern "C-unwind" fn(opaque: *mut c_void, pixel_format: *mut JxlPixelFormat),
+    get_color_channels_data_at: extern "C-unwind" fn(
+        opaque: *mut c_void,
+        xpos: usize,
+        ypos: usize,
+        xsize: usize,
+        ysize: usize,
+        row_offset: *mut usize,
+    ) -> *const c_void,
+    get_extra_channel_pixel_format: extern "C-unwind" fn(
+        opaque: *mut c_void,
+        ec_index: usize,
+        pixel_format: *mut JxlPixelFormat,
+    ),
+    get_extra_channel_data_at: extern "C-unwind" fn(
+        opaque: *mut c_void,
+        ec_index: usize,
+        xpos: usize,
+        ypos: usize,
+        xsize: usize,
+        ysize: usize,
+        row_offset: *mut usize,
+    ) -> *const c_void,
+    release_buffer: extern "C-unwind" fn(opaque: *mut c_void, buf: *const c_void),
+}
+
+const _: () = {
+    assert!(size_of::<OutputProcessor>() == size_of::<JxlEncoderOutputProcessor>());
+    assert!(align_of::<OutputProcessor>() == align_of::<JxlEncoderOutputProcessor>());
+    assert!(size_of::<ChunkedFrameInputSource>() == size_of::<JxlChunkedFrameInputSource>());
+    assert!(align_of::<ChunkedFrameInputSource>() == align_of::<JxlChunkedFrameInputSource>());
+};
+
+impl From<OutputProcessor> for JxlEncoderOutputProcessor {
+    fn from(processor: OutputProcessor) -> Self {
+        // SAFETY: same layout, see above
+        unsafe { std::mem::transmute(processor) }
+    }
+}
+
+impl From<ChunkedFrameInputSource> for JxlChunkedFrameInputSource {
+    fn from(source: ChunkedFrameInputSource) -> Self {
+        // SAFETY: same layout, see above
+        unsafe { std::mem::transmute(source) }
+    }
+}
+
+/// Destination for encoded bytes
+pub(crate) trait OutputSink: Send {
+    fn write(&mut self, data: &[u8]) -> PyResult<()>;
+}
+
+/// Writes to any Python object with a `write` method
+pub(crate) struct PyFileSink {
+    fp: Py<PyAny>,
+}
+
+impl PyFileSink {
+    pub(crate) fn new(fp: Py<PyAny>) -> Self {
+        Self { fp }
+    }
+}
+
+impl OutputSink for PyFileSink {
+    /// Copies at most one chunk at a time into Python, so that writing an
+    /// encoded image doesn't need a second full-size copy of it
+    fn write(&mut self, data: &[u8]) -> PyResult<()> {
+        Python::attach(|py| {
+            data.chunks(OUTPUT_CHUNK_SIZE).try_for_each(|chunk| {
+                self.fp
+                    .call_method1(py, "write", (PyBytes::new(py, chunk),))
+                    .map(drop)
+            })
+        })
+    }
+}
+
+/// Writes to a file on disk without touching the GIL
+pub(crate) struct FileSink {
+    file: BufWriter<File>,
+}
+
+impl FileSink {
+    pub(crate) fn create(path: &Path) -> PyResult<Self> {
+        Ok(Self {
+            file: BufWriter::new(File::create(path)?),
+        })
+    }
+
+    pub(crate) fn finish(self) -> PyResult<()> {
+        self.file
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        Ok(())
+    }
+}
+
+impl OutputSink for FileSink {
+    fn write(&mut self, data: &[u8]) -> PyResult<()> {
+        Ok(self.file.write_all(data)?)
+    }
+}
+
+/// Where the pixels of a streamed frame come from
+pub(crate) enum PixelSource<'a> {
+    /// `source(x, y, width, height)` returning the packed pixels of a tile
+    Tiles(Py<PyAny>),
+    /// An iterator yielding whole rows, any number of rows per item
+    Rows(RowBand),
+    /// The whole image, already in memory
+    /// Rows of `stride` bytes each, the last of which may be unpadded
+    Buffer(&'a [u8], usize),
+}
+
+pub(crate) struct RowBand {
+    rows: Py<PyIterator>,
+    /// Rows `first_row..first_row + data.len() / row_bytes` of the image
+    data: Vec<u8>,
+    first_row: usize,
+}
+
+impl PixelSource<'_> {
+    pub(crate) fn new(source: &Bound<'_, PyAny>) -> PyResult<Self> {
+        if source.is_callable() {
+            return Ok(PixelSource::Tiles(source.clone().unbind()));
+        }
+        let rows = source.try_iter().map_err(|_| {
+            PyValueError::new_err("Pixel source must be a callable or an iterable of rows")
+        })?;
+        Ok(PixelSource::Rows(RowBand {
+            rows: rows.unbind(),
+            data: Vec::new(),
+            first_row: 0,
+        }))
+    }
+
+    /// Read a tile as packed rows of `width * bytes_per_pixel` bytes
+    fn read(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        layout: Layout,
+    ) -> PyResult<Vec<u8>> {
+        let tile_bytes = width * height * layout.bytes_per_pixel;
+        match self {
+            PixelSource::Tiles(source) => Python::attach(|py| {
+                let tile = to_bytes(source.bind(py).call1((x, y, width, height))?)?;
+                if tile.len() != tile_bytes {
+                    return Err(PyValueError::new_err(format!(
+                        "Tile at ({x}, {y}) of size {width}x{height} must be {tile_bytes} bytes, got {}",
+                        tile.len()
+                    )));
+                }
+                Ok(tile)
+            }),
+            PixelSource::Rows(band) => {
+                let row_bytes = layout.width * layout.bytes_per_pixel;
+                if y < band.first_row {
+                    return Err(PyValueError::new_err(format!(
+                        "Row source cannot rewind to row {y}"
+                    )));
+                }
+                // Pull rows up to the end of the tile before dropping the
+                // ones above it, which may not have been read yet either
+                let skip = (y - band.first_row) * row_bytes;
+                while band.data.len() < skip + height * row_bytes {
+                    let chunk = Python::attach(|py| match band.rows.bind(py).clone().next() {
+                        Some(chunk) => to_bytes(chunk?).map(Some),
+                        None => Ok(None),
+                    })?;
+                    let Some(chunk) = chunk else {
+                        return Err(PyValueError::new_err(format!(
+                            "Row source ended before row {}",
+                            band.first_row + band.data.len() / row_bytes
+                        )));
+                    };
+                    if chunk.len() % row_bytes != 0 {
+                        return Err(PyValueError::new_err(format!(
+                            "Row chunk of {} bytes is not a multiple of the row size {row_bytes}",
+                            chunk.len()
+                        )));
+                    }
+                    band.data.extend_from_slice(&chunk);
+                }
+                band.data.drain(..skip);
+                band.first_row = y;
+                Ok(crop(&band.data, row_bytes, x, width, height, layout))
+            }
+            PixelSource::Buffer(data, stride) => Ok(crop(
+                &data[y * *stride..],
+                *stride,
+                x,
+                width,
+                height,
+                layout,
+            )),
+        }
+    }
+}
+
+/// Copy `width` pixels starting at column `x` out of the first `height` rows,
+/// which start every `stride` bytes
+fn crop(
+    rows: &[u8],
+    stride: usize,
+    x: usize,
+    width: usize,
+    height: usize,
+    layout: Layout,
+) -> Vec<u8> {
+    let mut tile = Vec::with_capacity(width * height * layout.bytes_per_pixel);
+    for row in 0..height {
+        let start = row * stride + x * layout.bytes_per_pixel;
+        tile.extend_from_slice(&rows[start..start + width * layout.bytes_per_pixel]);
+    }
+    tile
+}
+
+fn to_bytes(obj: Bound<'_, PyAny>) -> PyResult<Vec<u8>> {
+    let bytes = obj.py().get_type::<PyBytes>().call1((obj,))?;
+    Ok(bytes.extract::<&[u8]>()?.to_vec())
+}
+
+#[derive(Clone, Copy)]
+struct Layout {
+    width: usize,
+    bytes_per_pixel: usize,
+    bytes_per_sample: usize,
+}
+
+/// Lock `mutex` even if a panic poisoned it, since a panic unwinding out of
+/// a libjxl callback would cross C++ frames
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// State behind the `opaque` pointer of the chunked input callbacks
+struct FrameInput<'a> {
+    source: Mutex<PixelSource<'a>>,
+    layout: Layout,
+    pixel_format: JxlPixelFormat,
+    /// Layout of the source pixels, converted to RGB(A) tile by tile
+    format: BufferFormat,
+    has_alpha: bool,
+    /// Buffers handed to libjxl, keyed by address until released
+    buffers: Mutex<HashMap<usize, Vec<u8>>>,
+    /// Color or alpha samples of tiles read for the other of the two, keyed
+    /// by tile and whether they are alpha, so the source is read once per tile
+    pending: Mutex<HashMap<TileKey, Vec<u8>>>,
+    error: Mutex<Option<PyErr>>,
+}
+
+/// Position and size of a tile, and whether it holds the alpha samples
+type TileKey = (usize, usize, usize, usize, bool);
+
+impl FrameInput<'_> {
+    /// Read the interleaved samples of a tile, or with `alpha` only its alpha
+    /// samples. With an alpha channel, the part libjxl did not ask for is
+    /// kept until it does.
+    fn read_channels(
+        &self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        alpha: bool,
+    ) -> Vec<u8> {
+        if let Some(samples) = lock(&self.pending).remove(&(x, y, width, height, alpha)) {
+            return samples;
+        }
+        let tile = self.read(x, y, width, height);
+        if !self.has_alpha {
+            return tile;
+        }
+        let Layout {
+            bytes_per_pixel,
+            bytes_per_sample,
+            ..
+        } = self.layout;
+        let alpha_samples: Vec<u8> = tile
+            .chunks_exact(bytes_per_pixel)
+            .flat_map(|pixel| &pixel[bytes_per_pixel - bytes_per_sample..])
+            .copied()
+            .collect();
+        let (wanted, other) = match alpha {
+            true => (alpha_samples, tile),
+            false => (tile, alpha_samples),
+        };
+        lock(&self.pending).insert((x, y, width, height, !alpha), other);
+        wanted
+    }
+
+    fn read(&self, x: usize, y: usize, width: usize, height: usize) -> Vec<u8> {
+        // libjxl has no way to abort from an input callback, so once the
+        // source has failed it is handed zeroes without asking the source again
+        if lock(&self.error).is_some() {
+            return vec![0; width * height * self.layout.bytes_per_pixel];
+        }
+        let tile = lock(&self.source)
+            .read(x, y, width, height, self.layout)
+            .and_then(|mut tile| {
+                self.format.buffer_to_rgb(
+                    &mut tile,
+                    self.layout.bytes_per_sample,
+                    self.layout.bytes_per_pixel / self.layout.bytes_per_sample,
+                    self.has_alpha,
+                )?;
+                Ok(tile)
+            });
+        match tile {
+            Ok(tile) => tile,
+            Err(e) => {
+                // The error is reported once libjxl returns, which the
+                // output processor makes it do as soon as it can
+                lock(&self.error).get_or_insert(e);
+                vec![0; width * height * self.layout.bytes_per_pixel]
+            }
+        }
+    }
+
+    fn lend(&self, buffer: Vec<u8>) -> *const c_void {
+        let ptr = buffer.as_ptr();
+        lock(&self.buffers).insert(ptr as usize, buffer);
+        ptr.cast()
+    }
+}
+
+extern "C-unwind" fn get_color_channels_pixel_format(
+    opaque: *mut c_void,
+    pixel_format: *mut JxlPixelFormat,
+) {
+    let input = unsafe { &*(opaque as *const FrameInput) };
+    unsafe { *pixel_format = input.pixel_format };
+}
+
+extern "C-unwind" fn get_color_channels_data_at(
+    opaque: *mut c_void,
+    xpos: usize,
+    ypos: usize,
+    xsize: usize,
+    ysize: usize,
+    row_offset: *mut usize,
+) -> *const c_void {
+    let input = unsafe { &*(opaque as *const FrameInput) };
+    let tile = input.read_channels(xpos, ypos, xsize, ysize, false);
+    unsafe { *row_offset = xsize * input.layout.bytes_per_pixel };
+    input.lend(tile)
+}
+
+extern "C-unwind" fn get_extra_channel_pixel_format(
+    opaque: *mut c_void,
+    _ec_index: usize,
+    pixel_format: *mut JxlPixelFormat,
+) {
+    let input = unsafe { &*(opaque as *const FrameInput) };
+    unsafe {
+        *pixel_format = JxlPixelFormat {
+            num_channels: 1,
+            ..input.pixel_format
+        }
+    };
+}
+
+/// The only extra channel is alpha, which is interleaved as the last sample
+extern "C-unwind" fn get_extra_channel_data_at(
+    opaque: *mut c_void,
+    _ec_index: usize,
+    xpos: usize,
+    ypos: usize,
+    xsize: usize,
+    ysize: usize,
+    row_offset: *mut usize,
+) -> *const c_void {
+    let input = unsafe { &*(opaque as *const FrameInput) };
+    let alpha = input.read_channels(xpos, ypos, xsize, ysize, true);
+    unsafe { *row_offset = xsize * input.layout.bytes_per_sample };
+    input.lend(alpha)
+}
+
+extern "C-unwind" fn release_input_buffer(opaque: *mut c_void, buf: *const c_void) {
+    let input = unsafe { &*(opaque as *const FrameInput) };
+    lock(&input.buffers).remove(&(buf as usize));
+}
+
+/// State behind the `opaque` pointer of the output processor callbacks
+struct Output<'s> {
+    sink: &'s mut dyn OutputSink,
+    buffer: Vec<u8>,
+    written: u64,
+    error: Option<PyErr>,
+    /// Error of the pixel source, after which nothing more is written
+    input_error: &'s Mutex<Option<PyErr>>,
+}
+
+impl Output<'_> {
+    fn failed(&self) -> bool {
+        self.error.is_some() || lock(self.input_error).is_some()
+    }
+}
+
+extern "C-unwind" fn get_output_buffer(opaque: *mut c_void, size: *mut usize) -> *mut c_void {
+    let output = unsafe { &mut *(opaque as *mut Output) };
+    if output.failed() {
+        // A null buffer of size 0 asks libjxl to stop
+        unsafe { *size = 0 };
+        return null_mut();
+    }
+    let len = unsafe { *size }.max(OUTPUT_CHUNK_SIZE);
+    output.buffer.resize(len, 0);
+    unsafe { *size = len };
+    output.buffer.as_mut_ptr().cast()
+}
+
+extern "C-unwind" fn release_output_buffer(opaque: *mut c_void, written_bytes: usize) {
+    let output = unsafe { &mut *(opaque as *mut Output) };
+    // Output encoded from the zeroes standing in for failed reads is dropped
+    if output.failed() {
+        return;
+    }
+    match output.sink.write(&output.buffer[..written_bytes]) {
+        Ok(()) => output.written += written_bytes as u64,
+        Err(e) => output.error = Some(e),
+    }
+}
+
+extern "C-unwind" fn set_finalized_position(_opaque: *mut c_void, _finalized_position: u64) {}
+
+/// Owned libjxl encoder configured like [`Encoder::build_encoder`]
+struct RawEncoder {
+    enc: *mut JxlEncoder,
+    frame_settings: *mut JxlEncoderFrameSettings,
+}
+
+impl Drop for RawEncoder {
+    fn drop(&mut self) {
+        unsafe { JxlEncoderDestroy(self.enc) };
+    }
+}
+
+impl RawEncoder {
+    fn new(
+        encoder: &Encoder,
+        parallel_runner: &ThreadsRunner,
+        width: u32,
+        height: u32,
+    ) -> PyResult<Self> {
+        encoder.check_level(width, height, encoder.pixel_type)?;
+        let enc = unsafe { JxlEncoderCreate(null()) };
+        if enc.is_null() {
+            return Err(PyRuntimeError::new_err("Could not create JxlEncoder"));
+        }
+        let frame_settings = unsafe { JxlEncoderFrameSettingsCreate(enc, null()) };
+        let raw = Self {
+            enc,
+            frame_settings,
+        };
+
+        let pixel_type = encoder.pixel_type;
+        unsafe {
+            raw.check(JxlEncoderSetParallelRunner(
+                enc,
+                parallel_runner.runner(),
+                parallel_runner.as_opaque_ptr(),
+            ))?;
+            raw.check(JxlEncoderUseContainer(enc, encoder.use_container.into()))?;
+            // -1 lets libjxl pick level 5 or 10 from the basic info
+            raw.check(JxlEncoderSetCodestreamLevel(
+                enc,
+                encoder.level.map_or(-1, i32::from),
+            ))?;
+            raw.check(JxlEncoderSetFrameLossless(
+                frame_settings,
+                encoder.lossless.into(),
+            ))?;
+            raw.check(JxlEncoderFrameSettingsSetOption(
+                frame_settings,
+                JxlEncoderFrameSettingId::Effort,
+                encoder.speed()? as _,
+            ))?;
+            raw.check(JxlEncoderSetFrameDistance(
+                frame_settings,
+                JxlEncoderDistanceFromQuality(encoder.quality),
+            ))?;
+            raw.check(JxlEncoderFrameSettingsSetOption(
+                frame_settings,
+                JxlEncoderFrameSettingId::DecodingSpeed,
+                encoder.decoding_speed,
+            ))?;
+        }
+
+        let mut basic_info = unsafe {
+            let mut info = MaybeUninit::uninit();
+            JxlEncoderInitBasicInfo(info.as_mut_ptr());
+            info.assume_init()
+        };
+        let (bits, exp) = pixel_type.bits_per_sample();
+        basic_info.xsize = width;
+        basic_info.ysize = height;
+        basic_info.have_container = encoder.use_container.into();
+        basic_info.uses_original_profile = encoder.use_original_profile.into();
+        basic_info.bits_per_sample = bits;
+        basic_info.exponent_bits_per_sample = exp;
+        if pixel_type.has_alpha() {
+            basic_info.num_extra_channels = 1;
+            basic_info.alpha_bits = bits;
+            basic_info.alpha_exponent_bits = exp;
+        }
+        if pixel_type.num_channels() - u32::from(pixel_type.has_alpha()) == 1 {
+            basic_info.num_color_channels = 1;
+        }
+        parallel_runner.callback_basic_info(&basic_info);
+        let color_encoding = JxlColorEncoding::from(&pixel_type.color_encoding());
+        unsafe {
+            raw.check(JxlEncoderSetBasicInfo(enc, &raw const basic_info))?;
+            raw.check(JxlEncoderSetColorEncoding(enc, &raw const color_encoding))?;
+        }
+        Ok(raw)
+    }
+
+    fn check(&self, status: JxlEncoderStatus) -> PyResult<()> {
+        match status {
+            JxlEncoderStatus::Success => Ok(()),
+            _ => Err(PyRuntimeError::new_err(format!(
+                "Streaming encode failed: {}",
+                encoder_error_message(unsafe { JxlEncoderGetError(self.enc) })
+            ))),
+        }
+    }
+
+    fn add_boxes(&self, boxes: &[(&[u8; 4], &[u8])], compress: bool) -> PyResult<()> {
+        if boxes.is_empty() {
+            return Ok(());
+        }
+        unsafe {
+            self.check(JxlEncoderUseBoxes(self.enc))?;
+            for (box_type, contents) in boxes {
+                let box_type = JxlBoxType(box_type.map(|b| b as _));
+                self.check(JxlEncoderAddBox(
+                    self.enc,
+                    &raw const box_type,
+                    contents.as_ptr(),
+                    contents.len(),
+                    compress.into(),
+                ))?;
+            }
+            JxlEncoderCloseBoxes(self.enc);
+        }
+        Ok(())
+    }
+}
+
+fn encoder_error_message(error: JxlEncoderError) -> &'static str {
+    match error {
+        JxlEncoderError::OK => "no error",
+        JxlEncoderError::Generic => "generic error",
+        JxlEncoderError::OutOfMemory => "out of memory",
+        JxlEncoderError::Jbrd => "JPEG bitstream reconstruction data could not be represented",
+        JxlEncoderError::BadInput => "bad input",
+        JxlEncoderError::NotSupported => "not supported",
+        JxlEncoderError::ApiUsage => "API usage error",
+    }
+}
+
+impl Encoder {
+    /// Encode a single frame pulled from `source`, writing the output to `sink`.
+    /// Returns the number of bytes written.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn encode_stream_inner(
+        &self,
+        source: PixelSource<'_>,
+        sink: &mut dyn OutputSink,
+        width: u32,
+        height: u32,
+        exif: Option<&[u8]>,
+        jumb: Option<&[u8]>,
+        xmp: Option<&[u8]>,
+        compress: bool,
+    ) -> PyResult<u64> {
+        if width == 0 || height == 0 {
+            return Err(PyValueError::new_err("Image dimensions must be non-zero"));
+        }
+        let parallel_runner = self.parallel_runner()?;
+        let raw = RawEncoder::new(self, &parallel_runner, width, height)?;
+        let exif = exif.map(exif::to_box).transpose()?;
+        let boxes: Vec<(&[u8; 4], &[u8])> =
+            [(b"Exif", exif.as_deref()), (b"xml ", xmp), (b"jumb", jumb)]
+                .into_iter()
+                .filter_map(|(box_type, data)| data.map(|data| (box_type, data)))
+                .collect();
+        raw.add_boxes(&boxes, compress)?;
+
+        let pixel_type = self.pixel_type;
+        let input = FrameInput {
+            source: Mutex::new(source),
+            layout: Layout {
+                width: width as usize,
+                bytes_per_pixel: pixel_type.bytes_per_pixel(),
+                bytes_per_sample: (pixel_type.bits_per_sample().0 / 8) as usize,
+            },
+            pixel_format: JxlPixelFormat {
+                num_channels: pixel_type.num_channels(),
+                data_type: pixel_type.data_type(),
+                endianness: JxlEndianness::Native,
+                align: 0,
+            },
+            format: self.format,
+            has_alpha: pixel_type.has_alpha(),
+            buffers: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+            error: Mutex::new(None),
+        };
+        let mut output = Output {
+            sink,
+            buffer: Vec::new(),
+            written: 0,
+            error: None,
+            input_error: &input.error,
+        };
+
+        let status = unsafe {
+            raw.check(JxlEncoderSetOutputProcessor(
+                raw.enc,
+                OutputProcessor {
+                    opaque: (&raw mut output).cast(),
+                    get_buffer: get_output_buffer,
+                    release_buffer: release_output_buffer,
+                    seek: None,
+                    set_finalized_position,
+                }
+                .into(),
+            ))?;
+            JxlEncoderAddChunkedFrame(
+                raw.frame_settings,
+                true.into(),
+                ChunkedFrameInputSource {
+                    opaque: (&raw const input).cast_mut().cast(),
+                    get_color_channels_pixel_format,
+                    get_color_channels_data_at,
+                    get_extra_channel_pixel_format,
+                    get_extra_channel_data_at,
+                    release_buffer: release_input_buffer,
+                }
+                .into(),
+            )
+        };
+
+        let (written, output_error) = (output.written, output.error);
+        if let Some(e) = input
+            .error
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+        {
+            return Err(e);
+        }
+        if let Some(e) = output_error {
+            return Err(e);
+        }
+        raw.check(status)?;
+        Ok(written)
+    }
+}
@@ -0,0 +1,74 @@
+//! Framing of Exif metadata in JPEG XL `Exif` boxes.
+//!
+//! An `Exif` box holds a big-endian `u32` offset followed, after that many
+//! bytes, by the TIFF-structured Exif data. Pillow works with the bare TIFF
+//! data, optionally preceded by the `Exif\0\0` marker used in JPEG files.
+
+use std::borrow::Cow;
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+const JPEG_EXIF_MARKER: &[u8] = b"Exif\0\0";
+
+/// Check that `data` starts with a TIFF header whose first IFD lies within it
+fn validate_tiff(data: &[u8]) -> PyResult<()> {
+    let read_u32 = match data.get(..4) {
+        Some(b"II\x2a\x00") => u32::from_le_bytes,
+        Some(b"MM\x00\x2a") => u32::from_be_bytes,
+        _ => {
+            return Err(PyValueError::new_err(
+                "Exif data does not start with a TIFF header",
+            ))
+        }
+    };
+    let Some(ifd_offset) = data.get(4..8) else {
+        return Err(PyValueError::new_err("Exif TIFF header is truncated"));
+    };
+    let ifd_offset = read_u32(ifd_offset.try_into().unwrap()) as usize;
+    // The first IFD needs at least its 2-byte entry count
+    if ifd_offset < 8 || ifd_offset.saturating_add(2) > data.len() {
+        return Err(PyValueError::new_err(format!(
+            "Exif first IFD offset {ifd_offset} is outside the {} bytes of TIFF data",
+            data.len()
+        )));
+    }
+    Ok(())
+}
+
+/// The TIFF data framed by the contents of an `Exif` box.
+///
+/// Boxes written without the offset field, as older versions of this plugin
+/// did, are accepted too.
+pub(crate) fn from_box(contents: &[u8]) -> PyResult<&[u8]> {
+    if validate_tiff(contents).is_ok() {
+        return Ok(contents);
+    }
+    let Some(offset) = contents.get(..4) else {
+        return Err(PyValueError::new_err("Exif box is too short"));
+    };
+    let offset = u32::from_be_bytes(offset.try_into().unwrap()) as usize;
+    let Some(tiff) = contents.get(4usize.saturating_add(offset)..) else {
+        return Err(PyValueError::new_err(format!(
+            "Exif TIFF header offset {offset} is beyond the end of the {} byte box",
+            contents.len()
+        )));
+    };
+    validate_tiff(tiff)?;
+    Ok(tiff)
+}
+
+/// `Exif` box contents for Exif data as Pillow gives it: bare TIFF data,
+/// optionally after an `Exif\0\0` marker. Already framed box contents are
+/// validated and kept as they are.
+pub(crate) fn to_box(exif: &[u8]) -> PyResult<Cow<'_, [u8]>> {
+    let tiff = exif.strip_prefix(JPEG_EXIF_MARKER).unwrap_or(exif);
+    if validate_tiff(tiff).is_ok() {
+        let mut contents = Vec::with_capacity(4 + tiff.len());
+        contents.extend_from_slice(&0u32.to_be_bytes());
+        contents.extend_from_slice(tiff);
+        return Ok(Cow::Owned(contents));
+    }
+    from_box(exif)?;
+    Ok(Cow::Borrowed(exif))
+}
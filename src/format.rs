@@ -0,0 +1,160 @@
+//! Channel and byte order of the pixel buffers exchanged with Python.
+//!
+//! libjxl always works with RGB(A) samples in native byte order; other
+//! layouts are converted in place on the way in and out.
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+use crate::chunked;
+
+/// Order of the channels within a pixel
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub(crate) enum ChannelOrder {
+    #[default]
+    Rgb,
+    /// Red and blue swapped: BGR and BGRA, as used by OpenCV
+    Bgr,
+    /// Alpha first: ARGB, or AL for grayscale
+    Argb,
+}
+
+impl ChannelOrder {
+    pub(crate) fn from_name(name: &str) -> PyResult<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "RGB" | "RGBA" => Ok(ChannelOrder::Rgb),
+            "BGR" | "BGRA" => Ok(ChannelOrder::Bgr),
+            "ARGB" => Ok(ChannelOrder::Argb),
+            _ => Err(PyValueError::new_err(format!(
+                "Unknown channel order {name:?}, expected RGB, BGR or ARGB"
+            ))),
+        }
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            ChannelOrder::Rgb => "RGB",
+            ChannelOrder::Bgr => "BGR",
+            ChannelOrder::Argb => "ARGB",
+        }
+    }
+}
+
+/// Byte order of multi-byte samples
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub(crate) enum Endianness {
+    #[default]
+    Native,
+    Little,
+    Big,
+}
+
+impl Endianness {
+    pub(crate) fn from_name(name: &str) -> PyResult<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "native" => Ok(Endianness::Native),
+            "little" => Ok(Endianness::Little),
+            "big" => Ok(Endianness::Big),
+            _ => Err(PyValueError::new_err(format!(
+                "Unknown endianness {name:?}, expected native, little or big"
+            ))),
+        }
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Endianness::Native => "native",
+            Endianness::Little => "little",
+            Endianness::Big => "big",
+        }
+    }
+
+    fn is_native(self) -> bool {
+        match self {
+            Endianness::Native => true,
+            Endianness::Little => cfg!(target_endian = "little"),
+            Endianness::Big => cfg!(target_endian = "big"),
+        }
+    }
+}
+
+/// Layout of the samples in a buffer passed to or returned from Python
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub(crate) struct BufferFormat {
+    pub(crate) channel_order: ChannelOrder,
+    pub(crate) endianness: Endianness,
+}
+
+impl BufferFormat {
+    pub(crate) fn new(channel_order: &str, endianness: &str) -> PyResult<Self> {
+        Ok(Self {
+            channel_order: ChannelOrder::from_name(channel_order)?,
+            endianness: Endianness::from_name(endianness)?,
+        })
+    }
+
+    /// Whether buffers in this format are already native RGB(A)
+    pub(crate) fn is_native(&self) -> bool {
+        self.channel_order == ChannelOrder::Rgb && self.endianness.is_native()
+    }
+
+    /// Convert packed native RGB(A) samples to this format in place
+    pub(crate) fn rgb_to_buffer(
+        &self,
+        data: &mut [u8],
+        sample_size: usize,
+        num_channels: usize,
+        has_alpha: bool,
+    ) -> PyResult<()> {
+        self.convert(data, sample_size, num_channels, has_alpha, false)
+    }
+
+    /// Convert packed samples in this format to native RGB(A) in place
+    pub(crate) fn buffer_to_rgb(
+        &self,
+        data: &mut [u8],
+        sample_size: usize,
+        num_channels: usize,
+        has_alpha: bool,
+    ) -> PyResult<()> {
+        self.convert(data, sample_size, num_channels, has_alpha, true)
+    }
+
+    fn convert(
+        &self,
+        data: &mut [u8],
+        sample_size: usize,
+        num_channels: usize,
+        has_alpha: bool,
+        to_rgb: bool,
+    ) -> PyResult<()> {
+        let pixel_size = sample_size * num_channels;
+        let reorder = match self.channel_order {
+            ChannelOrder::Rgb => false,
+            ChannelOrder::Bgr => num_channels >= 3,
+            ChannelOrder::Argb => has_alpha,
+        };
+        let swap_bytes = sample_size > 1 && !self.endianness.is_native();
+        if !reorder && !swap_bytes {
+            return Ok(());
+        }
+        chunked::for_rows(data, pixel_size, |data| {
+            if reorder {
+                for pixel in data.chunks_exact_mut(pixel_size) {
+                    match self.channel_order {
+                        ChannelOrder::Bgr => {
+                            let (red, rest) = pixel.split_at_mut(sample_size);
+                            red.swap_with_slice(&mut rest[sample_size..2 * sample_size]);
+                        }
+                        ChannelOrder::Argb if to_rgb => pixel.rotate_left(sample_size),
+                        ChannelOrder::Argb => pixel.rotate_right(sample_size),
+                        ChannelOrder::Rgb => {}
+                    }
+                }
+            }
+            if swap_bytes {
+                data.chunks_exact_mut(sample_size).for_each(<[u8]>::reverse);
+            }
+        })
+    }
+}
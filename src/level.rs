@@ -0,0 +1,51 @@
+//! Codestream levels and the limits of level 5, the level every decoder
+//! supports. Level 10 lifts most of them and is declared by a `jxll` box.
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+/// Level of codestreams without a `jxll` box
+pub(crate) const DEFAULT_LEVEL: u8 = 5;
+
+const LEVEL5_MAX_SIDE: u64 = 1 << 18;
+const LEVEL5_MAX_PIXELS: u64 = 1 << 28;
+const LEVEL5_MAX_BITS_PER_SAMPLE: u32 = 16;
+const LEVEL5_MAX_ICC_SIZE: usize = 1 << 22;
+
+pub(crate) fn validate(level: u8) -> PyResult<u8> {
+    match level {
+        5 | 10 => Ok(level),
+        _ => Err(PyValueError::new_err(format!(
+            "Unknown codestream level {level}, expected 5 or 10"
+        ))),
+    }
+}
+
+/// The first level 5 limit an image exceeds, if any
+pub(crate) fn level5_violation(
+    width: u32,
+    height: u32,
+    bits_per_sample: u32,
+    icc_size: usize,
+) -> Option<String> {
+    let (width, height) = (u64::from(width), u64::from(height));
+    if width.max(height) > LEVEL5_MAX_SIDE {
+        Some(format!(
+            "{width}x{height} exceeds the level 5 limit of {LEVEL5_MAX_SIDE} pixels per side"
+        ))
+    } else if width * height > LEVEL5_MAX_PIXELS {
+        Some(format!(
+            "{width}x{height} exceeds the level 5 limit of {LEVEL5_MAX_PIXELS} pixels"
+        ))
+    } else if bits_per_sample > LEVEL5_MAX_BITS_PER_SAMPLE {
+        Some(format!(
+            "{bits_per_sample} bits per sample exceed the level 5 limit of {LEVEL5_MAX_BITS_PER_SAMPLE}"
+        ))
+    } else if icc_size > LEVEL5_MAX_ICC_SIZE {
+        Some(format!(
+            "A {icc_size} byte ICC profile exceeds the level 5 limit of {LEVEL5_MAX_ICC_SIZE} bytes"
+        ))
+    } else {
+        None
+    }
+}
@@ -1,16 +1,73 @@
-use pyo3::{create_exception, exceptions::PyRuntimeError, prelude::*};
+use std::ffi::CString;
+
+use pyo3::{
+    create_exception,
+    exceptions::{PyRuntimeError, PyUserWarning},
+    prelude::*,
+};
 
 // it works even if the item is not documented:
+mod array;
+mod batch;
+mod buffer;
+mod chunked;
+mod container;
 mod decode;
 mod encode;
+mod exif;
+mod format;
+mod level;
+mod logging;
+mod metrics;
+mod mmap;
+mod runner;
 
 create_exception!(my_module, JxlException, PyRuntimeError, "Jxl Error");
+create_exception!(
+    my_module,
+    JxlVerificationError,
+    JxlException,
+    "Decoded image does not match the encoder input"
+);
+
+create_exception!(
+    my_module,
+    JxlWarning,
+    PyUserWarning,
+    "Malformed JPEG XL data that was skipped or recovered from"
+);
+
+/// Emit a `JxlWarning` through Python's `warnings` module, which fails if
+/// warnings are configured to be raised as errors, and log it as well.
+pub(crate) fn warn(message: &str) -> PyResult<()> {
+    let c_message = CString::new(message)?;
+    Python::attach(|py| {
+        // Warnings are rare enough to look the level up each time
+        logging::refresh(py);
+        logging::log(logging::Level::Warning, || message.to_string());
+        PyErr::warn(py, &py.get_type::<JxlWarning>(), &c_message, 1)
+    })
+}
 
 #[pymodule]
 #[pyo3(name = "pillow_jxl")]
 fn pillow_jxl(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<buffer::PixelBuffer>()?;
+    m.add_class::<container::CodestreamPart>()?;
+    m.add_class::<container::JxlContainer>()?;
+    m.add_class::<decode::JxlBox>()?;
     m.add_class::<decode::Decoder>()?;
     m.add_class::<encode::Encoder>()?;
+    m.add_class::<metrics::CompareResult>()?;
+    m.add_function(wrap_pyfunction!(metrics::compare, m)?)?;
+    m.add_function(wrap_pyfunction!(container::strip_metadata, m)?)?;
+    m.add_function(wrap_pyfunction!(container::unwrap_container, m)?)?;
+    m.add_function(wrap_pyfunction!(container::wrap_codestream, m)?)?;
     m.add("JxlException", m.py().get_type::<JxlException>())?;
+    m.add(
+        "JxlVerificationError",
+        m.py().get_type::<JxlVerificationError>(),
+    )?;
+    m.add("JxlWarning", m.py().get_type::<JxlWarning>())?;
     Ok(())
 }
@@ -0,0 +1,86 @@
+//! Diagnostics sent through Python's `logging`, under the `pillow_jxl` logger.
+
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::Duration;
+
+use pyo3::marker::Ungil;
+use pyo3::prelude::*;
+use pyo3::sync::PyOnceLock;
+
+const LOGGER_NAME: &str = "pillow_jxl";
+
+static LOGGER: PyOnceLock<Py<PyAny>> = PyOnceLock::new();
+
+/// Lowest level the logger was enabled for when last looked up, so that
+/// `log` only takes the GIL for records that will be emitted
+static THRESHOLD: AtomicI32 = AtomicI32::new(0);
+
+/// Levels of the standard `logging` module
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Level {
+    Debug = 10,
+    Warning = 30,
+}
+
+fn logger(py: Python<'_>) -> PyResult<&Bound<'_, PyAny>> {
+    LOGGER
+        .get_or_try_init(py, || {
+            Ok(py
+                .import("logging")?
+                .call_method1("getLogger", (LOGGER_NAME,))?
+                .unbind())
+        })
+        .map(|logger| logger.bind(py))
+}
+
+/// Look up which levels the logger is enabled for
+pub(crate) fn refresh(py: Python<'_>) {
+    let threshold = logger(py).and_then(|logger| {
+        for level in [Level::Debug, Level::Warning] {
+            if logger
+                .call_method1("isEnabledFor", (level as i32,))?
+                .is_truthy()?
+            {
+                return Ok(level as i32);
+            }
+        }
+        Ok(i32::MAX)
+    });
+    match threshold {
+        Ok(threshold) => THRESHOLD.store(threshold, Ordering::Relaxed),
+        Err(e) => e.write_unraisable(py, None),
+    }
+}
+
+/// Release the GIL while running `f`, having looked up the enabled levels
+/// for the records it logs
+pub(crate) fn detach<T, F>(py: Python<'_>, f: F) -> T
+where
+    F: Ungil + FnOnce() -> T,
+    T: Ungil,
+{
+    refresh(py);
+    py.detach(f)
+}
+
+/// Log the message built by `message` at `level`. The message is only
+/// built when the logger was enabled for that level at the last `refresh`,
+/// and failures of the logging machinery are reported as unraisable rather
+/// than returned.
+pub(crate) fn log(level: Level, message: impl FnOnce() -> String) {
+    if (level as i32) < THRESHOLD.load(Ordering::Relaxed) {
+        return;
+    }
+    Python::attach(|py| {
+        let result =
+            logger(py).and_then(|logger| logger.call_method1("log", (level as i32, message())));
+        if let Err(e) = result {
+            e.write_unraisable(py, None);
+        }
+    })
+}
+
+/// Milliseconds, for timings in log messages
+pub(crate) fn millis(elapsed: Duration) -> f64 {
+    elapsed.as_secs_f64() * 1000.0
+}
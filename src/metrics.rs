@@ -0,0 +1,508 @@
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+use crate::decode::{is_jxl, Decoder, FloatImage};
+use crate::encode::{PixelType, SampleType};
+
+/// An image in linear sRGB with planar float channels in [0, 1].
+///
+/// Grayscale inputs are replicated into all three color planes.
+pub(crate) struct LinearImage {
+    width: usize,
+    height: usize,
+    planes: [Vec<f32>; 3],
+    alpha: Option<Vec<f32>>,
+}
+
+fn srgb_to_linear(v: f32) -> f32 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+impl LinearImage {
+    /// Build an image from interleaved samples which are already normalized to [0, 1].
+    fn from_normalized(
+        samples: impl Iterator<Item = f32>,
+        width: u32,
+        height: u32,
+        num_channels: u32,
+        srgb_transfer: bool,
+    ) -> PyResult<Self> {
+        let (width, height) = (width as usize, height as usize);
+        let has_alpha = matches!(num_channels, 2 | 4);
+        let num_color = match num_channels {
+            1 | 2 => 1,
+            3 | 4 => 3,
+            _ => {
+                return Err(PyValueError::new_err(
+                    "image channels must be between 1 and 4",
+                ))
+            }
+        };
+        let num_pixels = width * height;
+        let mut planes = [
+            Vec::with_capacity(num_pixels),
+            Vec::with_capacity(num_pixels),
+            Vec::with_capacity(num_pixels),
+        ];
+        let mut alpha = has_alpha.then(|| Vec::with_capacity(num_pixels));
+
+        let mut samples = samples.peekable();
+        for _ in 0..num_pixels {
+            for plane in planes.iter_mut().take(num_color) {
+                let v = samples.next().ok_or_else(|| {
+                    PyValueError::new_err("Pixel buffer is smaller than the image dimensions")
+                })?;
+                plane.push(if srgb_transfer { srgb_to_linear(v) } else { v });
+            }
+            if let Some(alpha) = alpha.as_mut() {
+                let v = samples.next().ok_or_else(|| {
+                    PyValueError::new_err("Pixel buffer is smaller than the image dimensions")
+                })?;
+                alpha.push(v.clamp(0.0, 1.0));
+            }
+        }
+        if samples.peek().is_some() {
+            return Err(PyValueError::new_err(
+                "Pixel buffer is larger than the image dimensions",
+            ));
+        }
+        if num_color == 1 {
+            planes[1] = planes[0].clone();
+            planes[2] = planes[0].clone();
+        }
+        Ok(Self {
+            width,
+            height,
+            planes,
+            alpha,
+        })
+    }
+
+    /// Interleaved 8-bit sRGB samples (L, LA, RGB or RGBA).
+    fn from_srgb8(data: &[u8], width: u32, height: u32, num_channels: u32) -> PyResult<Self> {
+        let samples = data.iter().map(|&v| v as f32 / 255.0);
+        Self::from_normalized(samples, width, height, num_channels, true)
+    }
+
+    /// A decoded JPEG XL image, whose samples may exceed [0, 1].
+    pub(crate) fn from_float(image: FloatImage) -> PyResult<Self> {
+        Self::from_normalized(
+            image.samples.into_iter(),
+            image.width,
+            image.height,
+            image.num_channels,
+            image.srgb_transfer,
+        )
+    }
+
+    /// 16-bit linear grayscale samples.
+    fn from_linear16(data: &[u16], width: u32, height: u32) -> PyResult<Self> {
+        let samples = data.iter().map(|&v| v as f32 / 65535.0);
+        Self::from_normalized(samples, width, height, 1, false)
+    }
+
+    /// 32-bit float linear grayscale samples.
+    fn from_linear_f32(data: &[f32], width: u32, height: u32) -> PyResult<Self> {
+        Self::from_normalized(data.iter().copied(), width, height, 1, false)
+    }
+
+    /// Raw pixel bytes laid out as the encoder expects them for `pixel_type`.
+    ///
+    /// 8-bit modes are sRGB encoded, while `I;16` and `F` are linear, matching the
+    /// color encoding the encoder signals for each mode.
+    pub(crate) fn from_pixels(
+        pixel_type: PixelType,
+        data: &[u8],
+        width: u32,
+        height: u32,
+    ) -> PyResult<Self> {
+        match (pixel_type.sample_type, pixel_type.linear) {
+            (SampleType::Uint8, false) => {
+                Self::from_srgb8(data, width, height, pixel_type.num_channels)
+            }
+            (SampleType::Uint16, true) if pixel_type.num_channels == 1 => {
+                let data_u16: &[u16] = bytemuck::try_cast_slice(data).map_err(|e| {
+                    PyValueError::new_err(format!("Failed to cast I;16 data to u16 slice: {e}"))
+                })?;
+                Self::from_linear16(data_u16, width, height)
+            }
+            (SampleType::Float32, true) if pixel_type.num_channels == 1 => {
+                let data_f32: &[f32] = bytemuck::try_cast_slice(data).map_err(|e| {
+                    PyValueError::new_err(format!("Failed to cast F data to f32 slice: {e}"))
+                })?;
+                Self::from_linear_f32(data_f32, width, height)
+            }
+            _ => Err(PyValueError::new_err(
+                "Only RGB, RGBA, L, LA, I;16, F are supported.",
+            )),
+        }
+    }
+
+    /// Raw pixel bytes in a Pillow mode, as returned by the decoder.
+    pub(crate) fn from_mode(mode: &str, data: &[u8], width: u32, height: u32) -> PyResult<Self> {
+        // The decoder upcasts float16 pixels to float32
+        let mode = if mode == "F;16" { "F" } else { mode };
+        Self::from_pixels(PixelType::from_mode(mode)?, data, width, height)
+    }
+
+    /// Composite the image onto a uniform gray background, dropping the alpha channel.
+    fn blend(&self, background: f32) -> Self {
+        let planes = match &self.alpha {
+            Some(alpha) => self.planes.clone().map(|plane| {
+                plane
+                    .iter()
+                    .zip(alpha)
+                    .map(|(&v, &a)| v * a + background * (1.0 - a))
+                    .collect()
+            }),
+            None => self.planes.clone(),
+        };
+        Self {
+            width: self.width,
+            height: self.height,
+            planes,
+            alpha: None,
+        }
+    }
+
+    /// Halve the resolution by averaging 2x2 blocks in linear light.
+    fn downsample(&self) -> Self {
+        let width = self.width.div_ceil(2);
+        let height = self.height.div_ceil(2);
+        let planes = self.planes.each_ref().map(|plane| {
+            let mut out = vec![0.0f32; width * height];
+            for y in 0..height {
+                for x in 0..width {
+                    let mut sum = 0.0;
+                    let mut count = 0.0;
+                    for sy in (2 * y)..(2 * y + 2).min(self.height) {
+                        for sx in (2 * x)..(2 * x + 2).min(self.width) {
+                            sum += plane[sy * self.width + sx];
+                            count += 1.0;
+                        }
+                    }
+                    out[y * width + x] = sum / count;
+                }
+            }
+            out
+        });
+        Self {
+            width,
+            height,
+            planes,
+            alpha: None,
+        }
+    }
+
+    /// Convert to the positive XYB representation used by SSIMULACRA2.
+    fn to_xyb(&self) -> [Vec<f32>; 3] {
+        const BIAS: f32 = 0.003_793_073_3;
+        let bias_cbrt = BIAS.cbrt();
+        let opsin = [
+            [0.30, 0.622, 0.078],
+            [0.23, 0.692, 0.078],
+            [0.243_422_69, 0.204_767_44, 0.551_809_87],
+        ];
+        let n = self.width * self.height;
+        let mut xyb = [
+            Vec::with_capacity(n),
+            Vec::with_capacity(n),
+            Vec::with_capacity(n),
+        ];
+        let [r, g, b] = &self.planes;
+        for ((&r, &g), &b) in r.iter().zip(g).zip(b) {
+            let mixed = opsin.map(|row| {
+                let v = row[0] * r + row[1] * g + row[2] * b + BIAS;
+                v.max(0.0).cbrt() - bias_cbrt
+            });
+            let x = 0.5 * (mixed[0] - mixed[1]);
+            let y = 0.5 * (mixed[0] + mixed[1]);
+            xyb[0].push(x * 14.0 + 0.42);
+            xyb[1].push(y + 0.01);
+            xyb[2].push((mixed[2] - y) + 0.55);
+        }
+        xyb
+    }
+
+    pub(crate) fn dimensions(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+}
+
+/// Separable Gaussian blur truncated at 3 sigma, with weights renormalized at the borders.
+fn blur(plane: &[f32], width: usize, height: usize, sigma: f32) -> Vec<f32> {
+    let radius = (3.0 * sigma).ceil() as usize;
+    let kernel: Vec<f32> = (0..=2 * radius)
+        .map(|i| {
+            let d = i as f32 - radius as f32;
+            (-d * d / (2.0 * sigma * sigma)).exp()
+        })
+        .collect();
+
+    // Horizontal pass, one row at a time
+    let mut tmp = vec![0.0; width * height];
+    for (src, dst) in plane.chunks_exact(width).zip(tmp.chunks_exact_mut(width)) {
+        for (x, out) in dst.iter_mut().enumerate() {
+            let start = x.saturating_sub(radius);
+            let end = (x + radius).min(width - 1);
+            let weights = &kernel[start + radius - x..=end + radius - x];
+            let sum: f32 = weights
+                .iter()
+                .zip(&src[start..=end])
+                .map(|(w, v)| w * v)
+                .sum();
+            *out = sum / weights.iter().sum::<f32>();
+        }
+    }
+
+    // Vertical pass, accumulating whole weighted rows
+    let mut out = vec![0.0; width * height];
+    for (y, dst) in out.chunks_exact_mut(width).enumerate() {
+        let start = y.saturating_sub(radius);
+        let end = (y + radius).min(height - 1);
+        let weights = &kernel[start + radius - y..=end + radius - y];
+        for (w, src) in weights.iter().zip(tmp[start * width..].chunks_exact(width)) {
+            for (out, v) in dst.iter_mut().zip(src) {
+                *out += w * v;
+            }
+        }
+        let norm = weights.iter().sum::<f32>().recip();
+        dst.iter_mut().for_each(|out| *out *= norm);
+    }
+    out
+}
+
+fn multiply(a: &[f32], b: &[f32]) -> Vec<f32> {
+    a.iter().zip(b).map(|(x, y)| x * y).collect()
+}
+
+/// Per-scale statistics: 2 SSIM norms and 4 edge difference norms per XYB channel.
+struct ScaleStats {
+    ssim: [f64; 6],
+    edge_diff: [f64; 12],
+}
+
+fn scale_stats(
+    img1: &[Vec<f32>; 3],
+    img2: &[Vec<f32>; 3],
+    width: usize,
+    height: usize,
+) -> ScaleStats {
+    const C2: f32 = 0.0009;
+    const SSIM_SIGMA: f32 = 1.5;
+    let n = (width * height) as f64;
+    let mut stats = ScaleStats {
+        ssim: [0.0; 6],
+        edge_diff: [0.0; 12],
+    };
+    for c in 0..3 {
+        let (p1, p2) = (&img1[c], &img2[c]);
+        let mu1 = blur(p1, width, height, SSIM_SIGMA);
+        let mu2 = blur(p2, width, height, SSIM_SIGMA);
+        let sigma11 = blur(&multiply(p1, p1), width, height, SSIM_SIGMA);
+        let sigma22 = blur(&multiply(p2, p2), width, height, SSIM_SIGMA);
+        let sigma12 = blur(&multiply(p1, p2), width, height, SSIM_SIGMA);
+
+        let (mut ssim_l1, mut ssim_l4) = (0.0f64, 0.0f64);
+        let (mut artifact_l1, mut artifact_l4) = (0.0f64, 0.0f64);
+        let (mut lost_l1, mut lost_l4) = (0.0f64, 0.0f64);
+        for i in 0..width * height {
+            let (m1, m2) = (mu1[i], mu2[i]);
+            let num_m = 1.0 - (m1 - m2) * (m1 - m2);
+            let num_s = 2.0 * (sigma12[i] - m1 * m2) + C2;
+            let denom_s = (sigma11[i] - m1 * m1) + (sigma22[i] - m2 * m2) + C2;
+            let d = f64::from((1.0 - num_m * num_s / denom_s).max(0.0));
+            ssim_l1 += d;
+            ssim_l4 += d.powi(4);
+
+            let d1 = f64::from((1.0 + (p2[i] - m2).abs()) / (1.0 + (p1[i] - m1).abs()) - 1.0);
+            let artifact = d1.max(0.0);
+            let lost = (-d1).max(0.0);
+            artifact_l1 += artifact;
+            artifact_l4 += artifact.powi(4);
+            lost_l1 += lost;
+            lost_l4 += lost.powi(4);
+        }
+        stats.ssim[c * 2] = ssim_l1 / n;
+        stats.ssim[c * 2 + 1] = (ssim_l4 / n).sqrt().sqrt();
+        stats.edge_diff[c * 4] = artifact_l1 / n;
+        stats.edge_diff[c * 4 + 1] = (artifact_l4 / n).sqrt().sqrt();
+        stats.edge_diff[c * 4 + 2] = lost_l1 / n;
+        stats.edge_diff[c * 4 + 3] = (lost_l4 / n).sqrt().sqrt();
+    }
+    stats
+}
+
+const SSIMULACRA2_SCALES: usize = 6;
+
+#[rustfmt::skip]
+const SSIMULACRA2_WEIGHTS: [f64; 108] = [
+    0.0, 0.000_737_660_670_740_658_6, 0.0, 0.0, 0.000_779_348_168_286_730_9, 0.0,
+    0.0, 0.000_437_115_573_010_737_9, 0.0, 1.104_172_642_665_734_6, 0.000_662_848_341_292_71, 0.000_152_316_327_837_187_52,
+    0.0, 0.001_640_643_745_659_975_4, 0.0, 1.842_245_552_053_929_8, 11.441_172_603_757_666, 0.0,
+    0.000_798_910_943_601_516_3, 0.000_176_816_438_078_653, 0.0, 1.878_759_497_954_638_7, 10.949_069_906_051_42, 0.0,
+    0.000_728_934_699_150_807_2, 0.967_793_708_062_683_3, 0.0, 0.000_140_034_242_854_358_84, 0.998_176_697_785_496_7, 0.000_319_497_559_344_350_53,
+    0.000_455_099_211_379_206_3, 0.0, 0.0, 0.001_364_876_616_324_339_8, 0.0, 0.0,
+    0.0, 0.0, 0.0, 7.466_890_328_078_848, 0.0, 17.445_833_984_131_262,
+    0.000_623_560_163_404_146_6, 0.0, 0.0, 6.683_678_146_179_332, 0.000_377_244_079_796_112_96, 1.027_889_937_768_264,
+    225.205_153_008_492_74, 0.0, 0.0, 19.213_238_186_143_016, 0.001_140_152_458_661_836_1, 0.001_237_755_635_509_985,
+    176.393_175_984_506_94, 0.0, 0.0, 24.433_009_998_704_76, 0.285_208_026_121_177_57, 0.000_448_543_692_383_340_8,
+    0.0, 0.0, 0.0, 34.779_063_444_837_72, 44.835_625_328_877_896, 0.0,
+    0.0, 0.0, 0.0, 0.0, 0.0, 0.0,
+    0.0, 0.000_868_055_657_329_169_8, 0.0, 0.0, 0.0, 0.0,
+    0.0, 0.000_531_319_187_435_874_7, 0.0, 0.000_165_338_141_613_791_12, 0.0, 0.0,
+    0.0, 0.0, 0.0, 0.000_417_917_180_325_133_6, 0.001_729_082_823_472_283_3, 0.0,
+    0.002_082_700_584_663_643_7, 0.0, 0.0, 8.826_982_764_996_862, 23.192_433_439_989_26, 0.0,
+    95.108_049_881_108_6, 0.986_397_803_440_068_2, 0.983_438_279_246_535_3, 0.001_228_640_504_827_849_3, 171.266_725_589_730_7, 0.980_785_887_243_537_9,
+    0.0, 0.0, 0.0, 0.000_513_006_458_899_067_9, 0.0, 0.000_108_540_578_584_115_37,
+];
+
+fn ssimulacra2_opaque(reference: &LinearImage, distorted: &LinearImage) -> f64 {
+    let mut scales = Vec::with_capacity(SSIMULACRA2_SCALES);
+    let mut downsampled: Option<(LinearImage, LinearImage)> = None;
+    for scale in 0..SSIMULACRA2_SCALES {
+        if scale > 0 {
+            let (img1, img2) = downsampled
+                .as_ref()
+                .map_or((reference, distorted), |(a, b)| (a, b));
+            downsampled = Some((img1.downsample(), img2.downsample()));
+        }
+        let (img1, img2) = downsampled
+            .as_ref()
+            .map_or((reference, distorted), |(a, b)| (a, b));
+        if img1.width < 8 || img1.height < 8 {
+            break;
+        }
+        scales.push(scale_stats(
+            &img1.to_xyb(),
+            &img2.to_xyb(),
+            img1.width,
+            img1.height,
+        ));
+    }
+
+    let mut score = 0.0;
+    let mut weights = SSIMULACRA2_WEIGHTS.iter();
+    for c in 0..3 {
+        for scale in 0..SSIMULACRA2_SCALES {
+            for n in 0..2 {
+                let values = scales.get(scale).map(|s| {
+                    [
+                        s.ssim[c * 2 + n],
+                        s.edge_diff[c * 4 + n],
+                        s.edge_diff[c * 4 + n + 2],
+                    ]
+                });
+                for k in 0..3 {
+                    let weight = weights.next().unwrap();
+                    if let Some(values) = values {
+                        score += weight * values[k].abs();
+                    }
+                }
+            }
+        }
+    }
+
+    score *= 0.956_238_261_683_484_4;
+    score = 2.326_765_642_916_932 * score - 0.020_884_521_182_843_837 * score * score
+        + 6.248_496_625_763_138e-05 * score * score * score;
+    if score > 0.0 {
+        100.0 - 10.0 * score.powf(0.627_633_646_783_138_7)
+    } else {
+        100.0
+    }
+}
+
+/// Compute the SSIMULACRA2 score between two images of the same size.
+///
+/// Images with alpha are composited onto a dark and a light background and the
+/// worse of the two scores is returned.
+pub(crate) fn ssimulacra2(reference: &LinearImage, distorted: &LinearImage) -> PyResult<f64> {
+    if reference.dimensions() != distorted.dimensions() {
+        return Err(PyValueError::new_err(
+            "Images must have the same dimensions",
+        ));
+    }
+    if reference.width < 8 || reference.height < 8 {
+        return Err(PyValueError::new_err(
+            "SSIMULACRA2 requires images of at least 8x8 pixels",
+        ));
+    }
+    if reference.alpha.is_none() && distorted.alpha.is_none() {
+        return Ok(ssimulacra2_opaque(reference, distorted));
+    }
+    let dark = ssimulacra2_opaque(&reference.blend(0.1), &distorted.blend(0.1));
+    let light = ssimulacra2_opaque(&reference.blend(0.9), &distorted.blend(0.9));
+    Ok(dark.min(light))
+}
+
+/// Perceptual distance between two images.
+#[pyclass(module = "pillow_jxl")]
+pub struct CompareResult {
+    /// SSIMULACRA2 score, 100 for identical images
+    #[pyo3(get)]
+    ssimulacra2: f64,
+}
+
+#[pymethods]
+impl CompareResult {
+    fn __repr__(&self) -> PyResult<String> {
+        Ok(format!("CompareResult(ssimulacra2={})", self.ssimulacra2))
+    }
+}
+
+/// Load either a JPEG XL image or a raw pixel buffer described by `mode` and size.
+fn load_image(
+    data: &[u8],
+    mode: Option<&str>,
+    size: Option<(u32, u32)>,
+    decoder: &Decoder,
+) -> PyResult<LinearImage> {
+    if is_jxl(data) {
+        let parallel_runner = decoder.parallel_runner()?;
+        return LinearImage::from_float(decoder.decode_srgb_float(data, &parallel_runner)?);
+    }
+    match (mode, size) {
+        (Some(mode), Some((width, height))) => LinearImage::from_mode(mode, data, width, height),
+        _ => Err(PyValueError::new_err(
+            "mode, width and height are required for raw pixel buffers",
+        )),
+    }
+}
+
+fn compare_inner(
+    reference: &[u8],
+    distorted: &[u8],
+    mode: Option<&str>,
+    size: Option<(u32, u32)>,
+    num_threads: isize,
+) -> PyResult<CompareResult> {
+    let decoder = Decoder::with_threads(num_threads);
+    let reference = load_image(reference, mode, size, &decoder)?;
+    let distorted = load_image(distorted, mode, size, &decoder)?;
+    Ok(CompareResult {
+        ssimulacra2: ssimulacra2(&reference, &distorted)?,
+    })
+}
+
+/// Compare a distorted image against a reference with SSIMULACRA2.
+///
+/// Each image is either an encoded JPEG XL file or raw pixels in `mode`.
+#[pyfunction]
+#[pyo3(signature = (reference, distorted, mode=None, width=None, height=None, num_threads=-1))]
+pub fn compare(
+    py: Python,
+    reference: &[u8],
+    distorted: &[u8],
+    mode: Option<&str>,
+    width: Option<u32>,
+    height: Option<u32>,
+    num_threads: isize,
+) -> PyResult<CompareResult> {
+    let size = width.zip(height);
+    py.detach(|| compare_inner(reference, distorted, mode, size, num_threads))
+}
@@ -0,0 +1,79 @@
+//! Thread pools kept alive across calls on the same `Encoder` or `Decoder`.
+
+use std::ops::Deref;
+use std::sync::{Mutex, MutexGuard, TryLockError};
+
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::prelude::*;
+
+use jpegxl_rs::parallel::threads_runner::ThreadsRunner;
+
+pub(crate) struct SharedRunner(ThreadsRunner<'static>);
+
+// libjxl's thread runner may be moved between threads, it just must not run
+// two jobs at once, which the surrounding mutex guarantees.
+unsafe impl Send for SharedRunner {}
+
+/// A lazily created `ThreadsRunner` reused by every call on its owner.
+///
+/// libjxl's runner executes one job at a time, so a call that finds the pool
+/// busy (the owner being used from several Python threads) gets a temporary
+/// runner instead of waiting.
+pub(crate) struct RunnerPool {
+    num_threads: isize,
+    runner: Mutex<Option<SharedRunner>>,
+}
+
+pub(crate) enum PooledRunner<'a> {
+    Shared(MutexGuard<'a, Option<SharedRunner>>),
+    Owned(ThreadsRunner<'static>),
+}
+
+impl Deref for PooledRunner<'_> {
+    type Target = ThreadsRunner<'static>;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            PooledRunner::Shared(guard) => &guard.as_ref().unwrap().0,
+            PooledRunner::Owned(runner) => runner,
+        }
+    }
+}
+
+impl RunnerPool {
+    pub(crate) fn new(num_threads: isize) -> Self {
+        Self {
+            num_threads,
+            runner: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn num_threads(&self) -> isize {
+        self.num_threads
+    }
+
+    fn create(&self) -> PyResult<ThreadsRunner<'static>> {
+        ThreadsRunner::new(
+            None,
+            if self.num_threads < 0 {
+                None
+            } else {
+                Some(self.num_threads as usize)
+            },
+        )
+        .ok_or_else(|| PyRuntimeError::new_err("Could not create JxlThreadsRunner"))
+    }
+
+    pub(crate) fn get(&self) -> PyResult<PooledRunner<'_>> {
+        let mut guard = match self.runner.try_lock() {
+            Ok(guard) => guard,
+            // A panic mid-call leaves the runner itself intact
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => return self.create().map(PooledRunner::Owned),
+        };
+        if guard.is_none() {
+            *guard = Some(SharedRunner(self.create()?));
+        }
+        Ok(PooledRunner::Shared(guard))
+    }
+}
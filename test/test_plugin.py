@@ -1,5 +1,12 @@
+import ctypes
+import hashlib
+import logging
 import os
+import shutil
+import subprocess
 import tempfile
+from concurrent.futures import ThreadPoolExecutor
+from io import BytesIO
 
 import numpy as np
 import OpenEXR
@@ -214,3 +221,1086 @@ def test_encode_F():
     assert img_random_enc.mode == "F"
     assert img_random.size == img_random_enc.size
     assert np.allclose(np.array(img_random), np.array(img_random_enc), atol=0)
+
+
+def test_encode_target_size():
+    img = Image.open("test/images/sample.png")
+    enc = pillow_jxl.Encoder(mode=img.mode)
+    data, size, distance = enc.encode_target(
+        img.tobytes(), img.width, img.height, target_size=1500
+    )
+    assert size == len(data) <= 1500
+    assert 0.05 <= distance <= 25.0
+
+    temp = tempfile.mktemp(suffix=".jxl")
+    img.save(temp, target_size=1500)
+    assert os.path.getsize(temp) <= 1500
+    assert Image.open(temp).size == img.size
+
+
+def test_encode_target_score():
+    img = Image.open("test/images/sample.png")
+    enc = pillow_jxl.Encoder(mode=img.mode)
+    data, size, distance = enc.encode_target(
+        img.tobytes(), img.width, img.height, target_score=80.0
+    )
+    assert size == len(data)
+    assert Image.open(BytesIO(data)).size == img.size
+    result = pillow_jxl.compare(
+        img.tobytes(), data, mode=img.mode, width=img.width, height=img.height
+    )
+    assert result.ssimulacra2 >= 80.0
+
+    with pytest.raises(ValueError):
+        enc.encode_target(img.tobytes(), img.width, img.height)
+
+
+def test_compare():
+    img = Image.open("test/images/sample.png")
+    with open("test/images/sample.jxl", "rb") as f:
+        jxl = f.read()
+
+    same = pillow_jxl.compare(jxl, jxl)
+    assert same.ssimulacra2 == 100.0
+
+    result = pillow_jxl.compare(
+        img.tobytes(), jxl, mode=img.mode, width=img.width, height=img.height
+    )
+    assert result.ssimulacra2 > 50.0
+
+    with pytest.raises(ValueError):
+        pillow_jxl.compare(img.tobytes(), jxl)
+
+
+def test_compare_sample_types():
+    # samples within half an 8-bit step of each other, only told apart above 8 bits
+    rng = np.random.default_rng(0)
+    arr = rng.integers(1, 255, (64, 64, 3), dtype=np.uint16) * 257
+    enc = pillow_jxl.Encoder(mode="RGB", lossless=True)
+    reference = enc.encode_array(arr)
+    distorted = enc.encode_array(arr + 100)
+    result = pillow_jxl.compare(reference, distorted)
+    assert result.ssimulacra2 < 100.0
+
+
+def test_compare_rejects_other_profiles():
+    with open("test/images/icc_profile/62AHB.jxl", "rb") as f:
+        jxl = f.read()
+    with pytest.raises(ValueError, match="sRGB"):
+        pillow_jxl.compare(jxl, jxl)
+
+
+def _reference_score(*args):
+    out = subprocess.run(args, check=True, capture_output=True, text=True).stdout
+    return [float(line.split()[-1]) for line in out.splitlines() if line.strip()]
+
+
+@pytest.mark.skipif(
+    not shutil.which("ssimulacra2"),
+    reason="libjxl's ssimulacra2 tool is not installed",
+)
+@pytest.mark.parametrize("quality", [95, 80, 50])
+def test_compare_reference_scores(tmp_path, quality):
+    img = Image.open("test/images/sample.png").convert("RGB")
+    enc = pillow_jxl.Encoder(mode="RGB", quality=quality)
+    data = enc(img.tobytes(), img.width, img.height, jpeg_encode=False)
+    reference, distorted = tmp_path / "reference.png", tmp_path / "distorted.png"
+    img.save(reference)
+    Image.open(BytesIO(data)).save(distorted)
+
+    result = pillow_jxl.compare(
+        img.tobytes(), data, mode="RGB", width=img.width, height=img.height
+    )
+    (score,) = _reference_score("ssimulacra2", str(reference), str(distorted))
+    assert result.ssimulacra2 == pytest.approx(score, abs=0.5)
+
+
+def test_encode_verify():
+    temp = tempfile.mktemp(suffix=".jxl")
+    img_png = Image.open("test/images/sample.png")
+    img_png.save(temp, lossless=True, verify=True)
+    assert np.array_equal(np.array(Image.open(temp)), np.array(img_png))
+
+    img_jpg = Image.open("test/images/sample.jpg")
+    img_jpg.save(temp, lossless_jpeg=True, verify=True)
+
+    with pytest.raises(ValueError):
+        img_png.save(temp, quality=90, verify=True)
+    assert issubclass(pillow_jxl.JxlVerificationError, pillow_jxl.JxlException)
+
+
+def test_encode_verify_mismatch():
+    img = Image.open("test/images/sample.png").convert("RGB")
+    data = img.tobytes()
+    enc = pillow_jxl.Encoder(mode="RGB", lossless=True)
+    encoded = enc(data, img.width, img.height, jpeg_encode=False)
+    enc._verify(encoded, data)
+
+    pixel = 3 * img.width + 5
+    altered = bytearray(data)
+    altered[pixel * 3 + 1] ^= 0xFF
+    message = f"at pixel {pixel}, sample {pixel * 3 + 1}$"
+    with pytest.raises(pillow_jxl.JxlVerificationError, match=message):
+        enc._verify(encoded, bytes(altered))
+
+    with open("test/images/sample.jpg", "rb") as f:
+        jpeg = f.read()
+    encoded = enc(jpeg, img.width, img.height, jpeg_encode=True)
+    enc._verify(encoded, jpeg, jpeg_encode=True)
+    altered = bytearray(jpeg)
+    altered[100] ^= 0xFF
+    with pytest.raises(pillow_jxl.JxlVerificationError, match="at byte 100$"):
+        enc._verify(encoded, bytes(altered), jpeg_encode=True)
+
+
+def test_encode_stream():
+    img = Image.open("test/images/sample.png").convert("RGB")
+    width, height = img.size
+    data = img.tobytes()
+    row_bytes = width * 3
+    enc = pillow_jxl.Encoder(mode="RGB", lossless=True)
+
+    def tile(x, y, w, h):
+        return img.crop((x, y, x + w, y + h)).tobytes()
+
+    rows = (data[y * row_bytes : (y + 7) * row_bytes] for y in range(0, height, 7))
+    single_rows = (data[y * row_bytes : (y + 1) * row_bytes] for y in range(height))
+    for source in (tile, rows, single_rows):
+        out = BytesIO()
+        written = enc.encode_stream(source, out, width, height)
+        assert written == out.tell()
+        out.seek(0)
+        assert np.array_equal(np.array(Image.open(out)), np.array(img))
+
+    with pytest.raises(ValueError):
+        enc.encode_stream(iter([data[:row_bytes]]), BytesIO(), width, height)
+
+    # with alpha, each tile is still read from the source once
+    rgba = Image.open("test/images/sample.png").convert("RGBA")
+    tiles = []
+
+    def rgba_tile(x, y, w, h):
+        tiles.append((x, y, w, h))
+        return rgba.crop((x, y, x + w, y + h)).tobytes()
+
+    out = BytesIO()
+    pillow_jxl.Encoder(mode="RGBA", lossless=True).encode_stream(
+        rgba_tile, out, width, height
+    )
+    assert len(tiles) == len(set(tiles))
+    out.seek(0)
+    assert np.array_equal(np.array(Image.open(out)), np.array(rgba))
+
+    calls = []
+
+    def failing(x, y, w, h):
+        calls.append((x, y))
+        raise RuntimeError("source failed")
+
+    out = BytesIO()
+    with pytest.raises(RuntimeError, match="source failed"):
+        enc.encode_stream(failing, out, width, height)
+    assert len(calls) == 1
+
+    # a failed encode to a path leaves no file behind
+    temp = tempfile.mktemp(suffix=".jxl")
+    with pytest.raises(ValueError):
+        enc.encode_to(temp, data[:-1], width, height, False, streaming=True)
+    assert not os.path.exists(temp)
+
+
+def test_encode_to():
+    img = Image.open("test/images/sample.png").convert("RGBA")
+    enc = pillow_jxl.Encoder(mode="RGBA", lossless=True)
+    expected = enc(img.tobytes(), img.width, img.height, jpeg_encode=False)
+
+    # by default the output is exactly that of __call__
+    out = BytesIO()
+    written = enc.encode_to(out, img.tobytes(), img.width, img.height, False)
+    assert out.getvalue() == expected
+    assert written == len(expected)
+
+    temp = tempfile.mktemp(suffix=".jxl")
+    assert enc.encode_to(temp, img.tobytes(), img.width, img.height, False) == written
+    with open(temp, "rb") as f:
+        assert f.read() == expected
+
+    streamed = BytesIO()
+    written = enc.encode_to(
+        streamed, img.tobytes(), img.width, img.height, False, streaming=True
+    )
+    assert written == len(streamed.getvalue())
+    decoded = Image.open(BytesIO(streamed.getvalue()))
+    assert np.array_equal(np.array(decoded), np.array(img))
+
+    for streaming in (False, True):
+        with pytest.raises(ValueError):
+            enc.encode_to(
+                BytesIO(),
+                img.tobytes()[:-1],
+                img.width,
+                img.height,
+                False,
+                streaming=streaming,
+            )
+
+
+class _RecordingFile:
+    def __init__(self):
+        self.sizes = []
+        self.data = bytearray()
+
+    def write(self, data):
+        self.sizes.append(len(data))
+        self.data += data
+        return len(data)
+
+
+def test_encode_to_writes_in_chunks():
+    # noise doesn't compress, so the output spans several chunks
+    arr = np.random.default_rng(0).integers(0, 256, (256, 256, 3), dtype=np.uint8)
+    enc = pillow_jxl.Encoder(mode="RGB", lossless=True)
+    expected = enc(arr.tobytes(), 256, 256, jpeg_encode=False)
+    assert len(expected) > 1 << 16
+
+    out = _RecordingFile()
+    assert enc.encode_to(out, arr.tobytes(), 256, 256, False) == len(expected)
+    assert bytes(out.data) == expected
+    assert len(out.sizes) > 1
+    assert max(out.sizes) <= 1 << 16
+
+    # saving through Pillow takes the same path
+    out = _RecordingFile()
+    Image.fromarray(arr).save(out, format="JXL", lossless=True)
+    assert max(out.sizes) <= 1 << 16
+
+
+def test_save_matches_encoder():
+    img = Image.open("test/images/sample.png").convert("RGB")
+    options = dict(quality=75, effort=5, decoding_speed=2)
+    enc = pillow_jxl.Encoder(mode="RGB", **options)
+    expected = enc(img.tobytes(), img.width, img.height, jpeg_encode=False)
+
+    out = BytesIO()
+    img.save(out, format="JXL", **options)
+    assert out.getvalue() == expected
+
+    # streaming is opt-in and stays close to the regular lossy output
+    out = BytesIO()
+    img.save(out, format="JXL", streaming=True, **options)
+    reference = np.array(Image.open(BytesIO(expected)), dtype=np.float32)
+    streamed = np.array(Image.open(BytesIO(out.getvalue())), dtype=np.float32)
+    assert np.abs(streamed - reference).mean() < 2
+
+
+def test_decode_file():
+    dec = pillow_jxl.Decoder()
+    path = "test/images/sample.jxl"
+    with open(path, "rb") as f:
+        expected = dec(f.read())
+    jpeg, info, data, icc, boxes = dec.decode_file(path)
+    assert jpeg == expected[0]
+    assert (info.mode, info.width, info.height) == (
+        expected[1].mode,
+        expected[1].width,
+        expected[1].height,
+    )
+    assert data == expected[2]
+    assert icc == expected[3]
+    assert len(boxes) == len(expected[4])
+
+    with pytest.raises(OSError):
+        dec.decode_file("test/images/does_not_exist.jxl")
+
+
+def test_open_ignores_unrelated_filename():
+    from pillow_jxl.JpegXLImagePlugin import JXLImageFile
+
+    with open("test/images/sample_grey.jxl", "rb") as f:
+        grey = f.read()
+    # a stream that isn't the named file must be decoded from the stream
+    img = JXLImageFile(BytesIO(grey), "test/images/sample.jxl")
+    assert img.mode == "I;16"
+
+    # as must a file read from past its start, e.g. an embedded image
+    with tempfile.NamedTemporaryFile(suffix=".bin") as f:
+        f.write(b"prefix" + grey)
+        f.flush()
+        f.seek(6)
+        img = JXLImageFile(f, f.name)
+        assert img.mode == "I;16"
+
+    with Image.open("test/images/sample.jxl") as img:
+        assert img.mode == "RGBA"
+
+
+class _PyBuffer(ctypes.Structure):
+    _fields_ = [
+        ("buf", ctypes.c_void_p),
+        ("obj", ctypes.c_void_p),
+        ("len", ctypes.c_ssize_t),
+        ("itemsize", ctypes.c_ssize_t),
+        ("readonly", ctypes.c_int),
+        ("ndim", ctypes.c_int),
+        ("format", ctypes.c_char_p),
+        ("shape", ctypes.POINTER(ctypes.c_ssize_t)),
+        ("strides", ctypes.POINTER(ctypes.c_ssize_t)),
+        ("suboffsets", ctypes.POINTER(ctypes.c_ssize_t)),
+        ("internal", ctypes.c_void_p),
+    ]
+
+
+def _buffer_layout(obj, flags):
+    """itemsize, len, format and shape of the buffer `obj` exports for `flags`"""
+    api = ctypes.pythonapi
+    api.PyObject_GetBuffer.argtypes = [
+        ctypes.py_object,
+        ctypes.POINTER(_PyBuffer),
+        ctypes.c_int,
+    ]
+    api.PyBuffer_Release.argtypes = [ctypes.POINTER(_PyBuffer)]
+    view = _PyBuffer()
+    assert api.PyObject_GetBuffer(obj, ctypes.byref(view), flags) == 0
+    try:
+        shape = [view.shape[i] for i in range(view.ndim)] if view.shape else None
+        return view.itemsize, view.len, view.format, shape
+    finally:
+        api.PyBuffer_Release(ctypes.byref(view))
+
+
+def test_decode_pixel_buffer():
+    dec = pillow_jxl.Decoder()
+    with open("test/images/sample.jxl", "rb") as f:
+        _, info, data, _, _ = dec(f.read())
+    arr = np.asarray(data)
+    assert arr.shape[:2] == (info.height, info.width)
+    assert arr.dtype == np.uint8
+    assert not arr.flags.writeable
+    assert memoryview(data).tobytes() == data.tobytes()
+    assert len(data) == arr.size
+    # hashlib asks for a plain buffer, without shape, and needs it flat
+    assert hashlib.sha256(data).digest() == hashlib.sha256(data.tobytes()).digest()
+    img = Image.frombuffer(
+        info.mode, (info.width, info.height), data, "raw", info.mode, 0, 1
+    )
+    assert np.array_equal(np.array(img), arr)
+
+    gray = np.random.default_rng(0).random((32, 48), dtype=np.float32)
+    encoded = pillow_jxl.Encoder(mode="F", lossless=True).encode_array(gray)
+    _, info, data, _, _ = dec(encoded)
+    assert info.mode == "F"
+    arr = np.asarray(data)
+    assert arr.shape == (info.height, info.width) == tuple(data.shape)
+    assert arr.dtype == np.float32
+    assert memoryview(data).format == data.format
+    # without a format, consumers get the float samples as flat bytes
+    PyBUF_STRIDES = 0x18
+    assert _buffer_layout(data, PyBUF_STRIDES) == (1, arr.nbytes, None, [arr.nbytes])
+
+
+def test_decode_into():
+    dec = pillow_jxl.Decoder()
+    with open("test/images/sample.jxl", "rb") as f:
+        data = f.read()
+    _, info, expected, _, _ = dec(data)
+    expected = np.asarray(expected)
+
+    out = np.zeros_like(expected)
+    for _ in range(2):
+        out[:] = 0
+        result = dec.decode_into(data, out)
+        assert (result.mode, result.width, result.height) == (
+            info.mode,
+            info.width,
+            info.height,
+        )
+        assert np.array_equal(out, expected)
+
+    raw = bytearray(expected.nbytes)
+    dec.decode_into(data, raw)
+    assert bytes(raw) == expected.tobytes()
+
+    with pytest.raises(ValueError):
+        dec.decode_into(data, bytearray(expected.nbytes - 1))
+    with pytest.raises(ValueError):
+        dec.decode_into(data, np.zeros(expected.shape, dtype=np.float32))
+    with pytest.raises((ValueError, BufferError, TypeError)):
+        dec.decode_into(data, bytes(expected.nbytes))
+
+
+def test_decode_into_matches_call():
+    # 16-bit samples go through the same dithering and clamping as __call__
+    rng = np.random.default_rng(0)
+    data = _encode_array(rng.integers(0, 65536, (64, 64, 3), dtype=np.uint16))
+    for dither in ("none", "ordered", "error-diffusion"):
+        dec = pillow_jxl.Decoder(dither=dither)
+        _, info, expected, _, _ = dec(data)
+        out = np.zeros_like(np.asarray(expected))
+        dec.decode_into(data, out)
+        assert np.array_equal(out, np.asarray(expected))
+
+    # level information accounts for the ICC profile, as __call__ does
+    with open("test/images/icc_profile/62AHB.jxl", "rb") as f:
+        data = f.read()
+    dec = pillow_jxl.Decoder()
+    _, info, _, icc, _ = dec(data)
+    assert icc
+    channels = info.num_channels + info.has_alpha_channel
+    result = dec.decode_into(data, bytearray(info.width * info.height * channels))
+    assert (result.level, result.exceeds_level5) == (info.level, info.exceeds_level5)
+
+    # metadata boxes are reported too
+    with open("test/images/metadata/1x1_exif_xmp.png.jxl", "rb") as f:
+        data = f.read()
+    _, info, _, _, _ = dec(data)
+    assert info.exif and info.xmp
+    channels = info.num_channels + info.has_alpha_channel
+    result = dec.decode_into(data, bytearray(info.width * info.height * channels))
+    assert (result.exif, result.xmp, result.jumbf) == (info.exif, info.xmp, info.jumbf)
+
+
+def test_thread_pool_reuse():
+    img = Image.open("test/images/sample.png").convert("RGB")
+    enc = pillow_jxl.Encoder(mode="RGB", quality=80, num_threads=2)
+    dec = pillow_jxl.Decoder(num_threads=2)
+    first = enc(img.tobytes(), img.width, img.height, jpeg_encode=False)
+    assert all(
+        enc(img.tobytes(), img.width, img.height, jpeg_encode=False) == first
+        for _ in range(3)
+    )
+
+    # concurrent calls on one instance fall back to temporary runners
+    with ThreadPoolExecutor(max_workers=4) as pool:
+        decoded = list(pool.map(lambda _: dec(first)[2].tobytes(), range(8)))
+    assert all(d == decoded[0] for d in decoded)
+
+
+def test_encode_decode_many():
+    imgs = [
+        Image.open("test/images/sample.png").convert("RGB").rotate(angle)
+        for angle in (0, 90, 180, 270)
+    ]
+    enc = pillow_jxl.Encoder(mode="RGB", lossless=True)
+    items = [(img.tobytes(), img.width, img.height) for img in imgs]
+    items.append((b"\x00", 10, 10))
+    encoded = enc.encode_many(items, max_workers=2)
+    assert len(encoded) == len(items)
+    assert isinstance(encoded[-1], Exception)
+    for img, data in zip(imgs, encoded):
+        assert data == enc(img.tobytes(), img.width, img.height, jpeg_encode=False)
+
+    # metadata and stride options apply to every image, as in __call__
+    exif, xmp = _tiff("little"), b"<x:xmpmeta/>"
+    (data,) = enc.encode_many(items[:1], exif=exif, xmp=xmp, compress=True)
+    assert data == enc(*items[0], jpeg_encode=False, exif=exif, xmp=xmp, compress=True)
+    width, height = imgs[0].size
+    rows = np.asarray(imgs[0]).reshape(height, width * 3)
+    padded = np.pad(rows, ((0, 0), (0, 4))).tobytes()
+    stride = width * 3 + 4
+    assert enc.encode_many([(padded, width, height)], stride=stride) == encoded[:1]
+
+    dec = pillow_jxl.Decoder()
+    decoded = dec.decode_many(encoded[:-1] + [b"not a jxl"])
+    assert isinstance(decoded[-1], Exception)
+    for img, (jpeg, info, data, _, _) in zip(imgs, decoded):
+        assert not jpeg
+        assert (info.width, info.height) == img.size
+        assert data.tobytes() == img.tobytes()
+
+    with pytest.raises(ValueError):
+        dec.decode_many([], max_workers=0)
+
+
+@pytest.mark.parametrize(
+    "dtype,channels",
+    [
+        (np.uint8, 3),
+        (np.uint8, 4),
+        (np.uint16, 3),
+        (np.float16, 1),
+        (np.float32, 1),
+        (np.float32, 3),
+    ],
+)
+def test_encode_decode_array(dtype, channels):
+    rng = np.random.default_rng(0)
+    shape = (32, 48) if channels == 1 else (32, 48, channels)
+    if np.issubdtype(dtype, np.integer):
+        arr = rng.integers(0, np.iinfo(dtype).max, shape, dtype=dtype)
+    else:
+        arr = rng.random(shape).astype(dtype)
+
+    # the mode only picks defaults, encode_array takes the layout from the array
+    enc = pillow_jxl.Encoder("RGB", lossless=True)
+    dec = pillow_jxl.Decoder()
+    decoded = np.asarray(dec.decode_array(enc.encode_array(arr)))
+    assert decoded.dtype == arr.dtype
+    assert np.array_equal(decoded, arr)
+
+    # strided views are packed on the fly
+    view = arr[::-1, ::2]
+    decoded = np.asarray(dec.decode_array(enc.encode_array(view)))
+    assert np.array_equal(decoded, view)
+
+
+def test_encode_array_invalid():
+    enc = pillow_jxl.Encoder("RGB")
+    with pytest.raises(ValueError):
+        enc.encode_array(np.zeros((4, 4), dtype=np.int32))
+    with pytest.raises(ValueError):
+        enc.encode_array(np.zeros((4, 4, 5), dtype=np.uint8))
+    with pytest.raises(ValueError):
+        enc.encode_array(np.zeros(16, dtype=np.uint8))
+
+
+def test_encode_stride():
+    img = Image.open("test/images/sample.png").convert("RGB")
+    arr = np.asarray(img)
+    enc = pillow_jxl.Encoder(mode="RGB", lossless=True, verify=True)
+    expected = enc(arr.tobytes(), img.width, img.height, jpeg_encode=False)
+
+    stride = img.width * 3 + 13
+    padded = np.zeros((img.height, stride), dtype=np.uint8)
+    padded[:, : img.width * 3] = arr.reshape(img.height, -1)
+    # the last row does not need its padding
+    data = padded.tobytes()[:-13]
+    encoded = enc(data, img.width, img.height, jpeg_encode=False, stride=stride)
+    assert encoded == expected
+
+    out = BytesIO()
+    enc.encode_to(out, data, img.width, img.height, jpeg_encode=False, stride=stride)
+    _, _, decoded, _, _ = pillow_jxl.Decoder()(out.getvalue())
+    assert decoded.tobytes() == arr.tobytes()
+
+    # cropped views are encoded in place
+    crop = arr[10:-10, 5:-5]
+    decoded = pillow_jxl.Decoder().decode_array(enc.encode_array(crop))
+    assert np.array_equal(np.asarray(decoded), crop)
+
+    with pytest.raises(ValueError):
+        enc(data, img.width, img.height, jpeg_encode=False, stride=img.width * 3 - 1)
+    with pytest.raises(ValueError):
+        enc(data[:-1], img.width, img.height, jpeg_encode=False, stride=stride)
+
+
+def test_channel_order():
+    img = Image.open("test/images/sample.png").convert("RGBA")
+    rgba = np.asarray(img)
+    bgra = np.ascontiguousarray(rgba[..., [2, 1, 0, 3]])
+    argb = np.ascontiguousarray(rgba[..., [3, 0, 1, 2]])
+    expected = pillow_jxl.Encoder(mode="RGBA", lossless=True)(
+        rgba.tobytes(), img.width, img.height, jpeg_encode=False
+    )
+
+    for order, arr in (("BGR", bgra), ("ARGB", argb)):
+        enc = pillow_jxl.Encoder(mode="RGBA", lossless=True, channel_order=order)
+        assert enc(arr.tobytes(), img.width, img.height, jpeg_encode=False) == expected
+        assert enc.encode_array(arr) == expected
+
+        dec = pillow_jxl.Decoder(channel_order=order)
+        _, _, data, _, _ = dec(expected)
+        assert np.array_equal(np.asarray(data), arr)
+        assert np.array_equal(np.asarray(dec.decode_array(expected)), arr)
+        out = np.zeros_like(arr)
+        dec.decode_into(expected, out)
+        assert np.array_equal(out, arr)
+
+    with pytest.raises(ValueError):
+        pillow_jxl.Decoder(channel_order="GBR")
+
+
+def test_endianness():
+    arr = np.arange(0, 65536, 64, dtype=np.uint16).reshape(32, 32)
+    enc = pillow_jxl.Encoder(mode="I;16", lossless=True)
+    expected = enc(arr.tobytes(), 32, 32, jpeg_encode=False)
+
+    big = pillow_jxl.Encoder(mode="I;16", lossless=True, endianness="big")
+    assert big(arr.astype(">u2").tobytes(), 32, 32, jpeg_encode=False) == expected
+
+    data = pillow_jxl.Decoder(endianness="big").decode_array(expected)
+    assert data.format == ">H"
+    assert np.array_equal(np.asarray(data), arr)
+    assert data.tobytes() == arr.astype(">u2").tobytes()
+
+    with pytest.raises(ValueError):
+        pillow_jxl.Encoder(mode="I;16", endianness="middle")
+
+
+def _encode_array(arr):
+    return pillow_jxl.Encoder(mode="RGB", lossless=True).encode_array(arr)
+
+
+def test_decode_rounding():
+    # 255 / 257 rounds up to 1 rather than truncating to 0
+    data = _encode_array(np.full((16, 16, 3), 255, dtype=np.uint16))
+    _, info, pixels, _, _ = pillow_jxl.Decoder()(data)
+    assert info.mode == "RGB"
+    assert np.all(np.asarray(pixels) == 1)
+
+
+@pytest.mark.parametrize("dither", ["ordered", "error-diffusion"])
+def test_decode_dither(dither):
+    # halfway between two 8-bit values
+    data = _encode_array(np.full((64, 64, 3), 33025, dtype=np.uint16))
+    _, _, rounded, _, _ = pillow_jxl.Decoder()(data)
+    assert np.all(np.asarray(rounded) == 129)
+
+    _, _, dithered, _, _ = pillow_jxl.Decoder(dither=dither)(data)
+    dithered = np.asarray(dithered)
+    assert set(np.unique(dithered)) == {128, 129}
+    assert abs(dithered.mean() - 33025 / 257) < 0.05
+
+    with pytest.raises(ValueError):
+        pillow_jxl.Decoder(dither="random")
+
+
+def test_decode_clamp():
+    arr = np.full((16, 16, 3), 0.5, dtype=np.float32)
+    arr[0, 0] = 1.5
+    data = _encode_array(arr)
+    _, _, pixels, _, _ = pillow_jxl.Decoder()(data)
+    assert np.asarray(pixels)[0, 0].tolist() == [255, 255, 255]
+    with pytest.raises(ValueError):
+        pillow_jxl.Decoder(clamp=False)(data)
+
+
+def test_decode_large_conversions():
+    # large enough to be split across threads
+    rng = np.random.default_rng(0)
+    arr = rng.integers(0, 65536, (1024, 1536, 3), dtype=np.uint16)
+    data = _encode_array(arr)
+    _, _, pixels, _, _ = pillow_jxl.Decoder()(data)
+    expected = np.floor(arr / 257 + 0.5).astype(np.uint8)
+    assert np.array_equal(np.asarray(pixels), expected)
+
+    # num_threads=0 converts on the calling thread with the same result
+    _, _, serial, _, _ = pillow_jxl.Decoder(num_threads=0)(data)
+    assert np.array_equal(np.asarray(serial), expected)
+
+    swapped = pillow_jxl.Decoder(channel_order="BGR", endianness="big")
+    assert np.array_equal(
+        np.asarray(swapped.decode_array(data)), arr[..., ::-1].astype(">u2")
+    )
+
+    # ordered dithering repeats every 8 rows regardless of how rows are split
+    _, _, ordered, _, _ = pillow_jxl.Decoder(dither="ordered")(data)
+    assert np.abs(np.asarray(ordered).astype(int) - expected).max() <= 1
+
+    gray = rng.random((1024, 1536)).astype(np.float16)
+    data = pillow_jxl.Encoder(mode="F", lossless=True).encode_array(gray)
+    _, info, pixels, _, _ = pillow_jxl.Decoder()(data)
+    assert info.mode == "F;16"
+    assert np.array_equal(np.asarray(pixels), gray.astype(np.float32))
+
+
+def test_metadata_xmp_jumbf_roundtrip():
+    with open("test/images/metadata/sample.xmp", "rb") as f:
+        xmp = f.read()
+    # a JUMBF superbox holding just its description box
+    label = b"test\x00"
+    jumd = (8 + 16 + 1 + len(label)).to_bytes(4, "big") + b"jumd"
+    jumd += bytes(range(16)) + b"\x03" + label
+    img = Image.open("test/images/sample.png")
+
+    first = BytesIO()
+    img.save(first, format="JXL", xmp=xmp, jumb=jumd, use_container=True)
+    _, info, _, _, boxes = pillow_jxl.Decoder()(first.getvalue())
+    assert info.xmp == xmp
+    assert info.jumbf == jumd
+    assert {b.box_type for b in boxes} >= {b"xml ", b"jumb"}
+
+    with Image.open(first) as reopened:
+        assert reopened.info["xmp"] == xmp
+        assert reopened.info["jumb"] == jumd
+        second = BytesIO()
+        reopened.save(second, format="JXL")
+    with Image.open(second) as resaved:
+        assert resaved.info["xmp"] == xmp
+        assert resaved.info["jumb"] == jumd
+
+
+def test_metadata_compressed_boxes():
+    with open("test/images/metadata/sample.exif", "rb") as f:
+        exif = f.read()
+    with open("test/images/metadata/sample.xmp", "rb") as f:
+        xmp = f.read()
+    img = Image.open("test/images/sample.png")
+    out = BytesIO()
+    img.save(out, format="JXL", exif=exif, xmp=xmp, compress_metadata=True)
+    assert b"brob" in out.getvalue()
+
+    _, info, _, _, boxes = pillow_jxl.Decoder()(out.getvalue())
+    compressed = {b.box_type: b for b in boxes if b.compressed}
+    assert set(compressed) == {b"Exif", b"xml "}
+    assert compressed[b"xml "].data == xmp
+    assert info.xmp == xmp
+    assert info.exif is not None
+
+    plain = BytesIO()
+    img.save(plain, format="JXL", exif=exif, xmp=xmp)
+    with Image.open(out) as reopened, Image.open(plain) as expected:
+        assert reopened.info["xmp"] == xmp
+        assert reopened.info["exif"] == expected.info["exif"]
+
+
+def _tiff(byteorder):
+    # a single IFD with the Make tag stored inline
+    order = b"II" if byteorder == "little" else b"MM"
+
+    def u16(v):
+        return v.to_bytes(2, byteorder)
+
+    def u32(v):
+        return v.to_bytes(4, byteorder)
+
+    return (
+        order
+        + u16(42)
+        + u32(8)
+        + u16(1)
+        + u16(0x010F)
+        + u16(2)
+        + u32(4)
+        + b"jxl\x00"
+        + u32(0)
+    )
+
+
+def _exif_box(decoded):
+    _, info, _, _, boxes = pillow_jxl.Decoder()(decoded)
+    return info.exif, next(b.data for b in boxes if b.box_type == b"Exif")
+
+
+@pytest.mark.parametrize("byteorder", ["little", "big"])
+def test_exif_framing(byteorder):
+    tiff = _tiff(byteorder)
+    img = Image.open("test/images/sample.png").convert("RGB")
+    enc = pillow_jxl.Encoder(mode="RGB", use_container=True)
+    for exif in (tiff, b"Exif\x00\x00" + tiff, b"\x00\x00\x00\x00" + tiff):
+        data = enc(img.tobytes(), img.width, img.height, jpeg_encode=False, exif=exif)
+        assert _exif_box(data) == (tiff, b"\x00\x00\x00\x00" + tiff)
+
+    # a non-zero offset skips the bytes before the TIFF header
+    data = enc(img.tobytes(), img.width, img.height, jpeg_encode=False)
+    contents = (6).to_bytes(4, "big") + b"Exif\x00\x00" + tiff
+    data += (8 + len(contents)).to_bytes(4, "big") + b"Exif" + contents
+    assert _exif_box(data) == (tiff, contents)
+    with Image.open(BytesIO(data)) as reopened:
+        assert reopened.getexif()[0x010F] == "jxl"
+
+    with pytest.raises(ValueError):
+        enc(img.tobytes(), img.width, img.height, jpeg_encode=False, exif=b"junk")
+    with pytest.raises(ValueError):
+        enc(img.tobytes(), img.width, img.height, jpeg_encode=False, exif=tiff[:6])
+    with pytest.raises(ValueError):
+        pillow_jxl.wrap_codestream(data, exif=b"junk")
+
+    # saving through Pillow drops invalid Exif instead
+    for exif in (b"junk", tiff[:6]):
+        out = BytesIO()
+        with pytest.warns(pillow_jxl.JxlWarning, match="Exif"):
+            img.save(out, format="JXL", exif=exif)
+        with Image.open(out) as reopened:
+            assert "exif" not in reopened.info
+
+
+def test_container_edit():
+    img = Image.open("test/images/sample.png").convert("RGB")
+    enc = pillow_jxl.Encoder(mode="RGB", use_container=True)
+    data = enc(
+        img.tobytes(), img.width, img.height, jpeg_encode=False, exif=_tiff("little")
+    )
+
+    container = pillow_jxl.JxlContainer(data)
+    types = [b.box_type for b in container.boxes]
+    assert types[:2] == [b"JXL ", b"ftyp"]
+    assert b"Exif" in types
+    assert container.tobytes() == data
+
+    container.add("xml ", b"<x:xmpmeta/>")
+    assert [b.box_type for b in container.boxes].index(b"xml ") < len(container) - 1
+    container.replace(b"Exif", b"\x00\x00\x00\x00" + _tiff("big"))
+    assert container.find("Exif").data == b"\x00\x00\x00\x00" + _tiff("big")
+    assert container.find("jumb") is None
+
+    edited = container.tobytes()
+    _, info, decoded, _, _ = pillow_jxl.Decoder()(edited)
+    assert info.xmp == b"<x:xmpmeta/>"
+    assert info.exif == _tiff("big")
+    assert decoded == pillow_jxl.Decoder()(data)[2]
+
+    container = pillow_jxl.JxlContainer(edited)
+    assert container.remove("Exif") == 1
+    assert container.remove("Exif") == 0
+    with Image.open(BytesIO(container.tobytes())) as reopened:
+        assert "exif" not in reopened.info
+        assert reopened.info["xmp"] == b"<x:xmpmeta/>"
+
+    with pytest.raises(KeyError):
+        container.replace("Exif", b"")
+    with pytest.raises(ValueError):
+        container.remove("ftyp")
+    with pytest.raises(ValueError):
+        container.add("xml ", b"", index=0)
+    with pytest.raises(ValueError):
+        container.find("toolong")
+    with pytest.raises(ValueError, match="byte"):
+        pillow_jxl.JxlContainer(data[:20])
+    codestream = pillow_jxl.Encoder(mode="RGB")(
+        img.tobytes(), img.width, img.height, jpeg_encode=False
+    )
+    with pytest.raises(ValueError):
+        pillow_jxl.JxlContainer(codestream)
+
+
+def test_strip_metadata():
+    img = Image.open("test/images/sample.png").convert("RGB")
+    enc = pillow_jxl.Encoder(mode="RGB", use_container=True)
+    data = enc(
+        img.tobytes(), img.width, img.height, jpeg_encode=False, exif=_tiff("little")
+    )
+    container = pillow_jxl.JxlContainer(data)
+    container.add("xml ", b"<x:xmpmeta/>")
+    data = container.tobytes()
+    pixels = pillow_jxl.Decoder()(data)[2]
+
+    stripped = pillow_jxl.strip_metadata(data)
+    assert stripped[:2] == b"\xff\x0a"
+    _, info, decoded, _, boxes = pillow_jxl.Decoder()(stripped)
+    assert decoded == pixels
+    assert info.exif is None and info.xmp is None and boxes == []
+
+    kept = pillow_jxl.strip_metadata(data, keep_container=True)
+    assert [b.box_type for b in pillow_jxl.JxlContainer(kept).boxes] == [
+        b"JXL ",
+        b"ftyp",
+        b"jxlc",
+    ]
+
+    only_exif = pillow_jxl.strip_metadata(data, ["Exif"])
+    _, info, decoded, _, _ = pillow_jxl.Decoder()(only_exif)
+    assert decoded == pixels
+    assert info.exif is None
+    assert info.xmp == b"<x:xmpmeta/>"
+
+    compressed = enc(
+        img.tobytes(),
+        img.width,
+        img.height,
+        jpeg_encode=False,
+        exif=_tiff("big"),
+        compress=True,
+    )
+    assert pillow_jxl.Decoder()(pillow_jxl.strip_metadata(compressed))[1].exif is None
+
+    assert pillow_jxl.strip_metadata(stripped) == stripped
+    with pytest.raises(ValueError):
+        pillow_jxl.strip_metadata(data, ["jxlc"])
+
+
+def _box(box_type, contents):
+    return (8 + len(contents)).to_bytes(4, "big") + box_type + contents
+
+
+def test_wrap_unwrap_codestream():
+    img = Image.open("test/images/sample.png").convert("RGB")
+    codestream = pillow_jxl.Encoder(mode="RGB")(
+        img.tobytes(), img.width, img.height, jpeg_encode=False
+    )
+    pixels = pillow_jxl.Decoder()(codestream)[2]
+
+    wrapped = pillow_jxl.wrap_codestream(
+        codestream, level=5, exif=_tiff("little"), xmp=b"<x:xmpmeta/>"
+    )
+    types = [b.box_type for b in pillow_jxl.JxlContainer(wrapped).boxes]
+    assert types == [b"JXL ", b"ftyp", b"jxll", b"Exif", b"xml ", b"jxlc"]
+    _, info, decoded, _, _ = pillow_jxl.Decoder()(wrapped)
+    assert decoded == pixels
+    assert info.exif == _tiff("little")
+    assert info.xmp == b"<x:xmpmeta/>"
+    with Image.open(BytesIO(wrapped)) as reopened:
+        assert np.array_equal(np.array(reopened), np.array(img))
+
+    assert pillow_jxl.unwrap_container(wrapped) == codestream
+    assert pillow_jxl.unwrap_container(codestream) == codestream
+
+    # the level 10 declaration would be lost along with the container
+    level10 = pillow_jxl.wrap_codestream(codestream, level=10)
+    with pytest.raises(ValueError, match="level 10"):
+        pillow_jxl.unwrap_container(level10)
+
+    # a codestream split over jxlp boxes, the last one flagged in its index
+    header = wrapped[:32]
+    split = len(codestream) // 2
+    parts = header + _box(b"jxlp", (0).to_bytes(4, "big") + codestream[:split])
+    parts += _box(b"jxlp", (1 | 1 << 31).to_bytes(4, "big") + codestream[split:])
+    assert pillow_jxl.Decoder()(parts)[2] == pixels
+    assert pillow_jxl.unwrap_container(parts) == codestream
+
+    with pytest.raises(ValueError):
+        pillow_jxl.wrap_codestream(wrapped)
+    with pytest.raises(ValueError):
+        pillow_jxl.wrap_codestream(codestream, level=7)
+    with pytest.raises(ValueError):
+        pillow_jxl.unwrap_container(header)
+
+
+def test_container_codestream_parts():
+    img = Image.open("test/images/sample.png").convert("RGB")
+    codestream = pillow_jxl.Encoder(mode="RGB")(
+        img.tobytes(), img.width, img.height, jpeg_encode=False
+    )
+    header = pillow_jxl.wrap_codestream(codestream)[:32]
+
+    def split(indices):
+        step = len(codestream) // len(indices) + 1
+        chunks = [codestream[i : i + step] for i in range(0, len(codestream), step)]
+        data = header
+        for index, chunk in zip(indices, chunks):
+            data += _box(b"jxlp", index.to_bytes(4, "big") + chunk)
+        return data
+
+    last = 1 << 31
+    data = split([0, 1, 2 | last])
+    container = pillow_jxl.JxlContainer(data)
+    assert container.codestream == codestream
+    parts = container.codestream_parts
+    assert [(p.index, p.is_last) for p in parts] == [
+        (0, False),
+        (1, False),
+        (2, True),
+    ]
+    assert sum(p.size for p in parts) == len(codestream)
+    for part in parts:
+        file_part = data[part.file_offset : part.file_offset + part.size]
+        offset = part.codestream_offset
+        assert file_part == codestream[offset : offset + part.size]
+
+    wrapped = pillow_jxl.JxlContainer(pillow_jxl.wrap_codestream(codestream))
+    (part,) = wrapped.codestream_parts
+    assert (part.index, part.is_last, part.size) == (0, True, len(codestream))
+
+    # boxes with a 64-bit size keep it, so offsets hold in the original file
+    large = (1).to_bytes(4, "big") + b"jxlc" + (16 + len(codestream)).to_bytes(8, "big")
+    data = header + _box(b"xml ", b"<x/>") + large + codestream
+    container = pillow_jxl.JxlContainer(data)
+    (part,) = container.codestream_parts
+    assert data[part.file_offset :] == codestream
+    assert container.tobytes() == data
+
+    for indices in ([1, 0, 2 | last], [0, 1, 2], [0, 1 | last, 2 | last]):
+        with pytest.raises(ValueError, match="byte|last"):
+            pillow_jxl.JxlContainer(split(indices)).codestream
+        with pytest.raises(ValueError):
+            pillow_jxl.unwrap_container(split(indices))
+
+
+def test_strict_box_parsing():
+    img = Image.open("test/images/sample.png").convert("RGB")
+    data = pillow_jxl.Encoder(mode="RGB", use_container=True)(
+        img.tobytes(), img.width, img.height, jpeg_encode=False
+    )
+    pixels = pillow_jxl.Decoder()(data)[2]
+    truncated = data + _box(b"xml ", b"<x:xmpmeta/>")[:-4]
+    invalid_exif = data + _box(b"Exif", b"\x00\x00\x00\x00junk")
+    invalid_brob = data + _box(b"brob", b"xml not brotli")
+
+    with pytest.warns(pillow_jxl.JxlWarning, match=f"byte {len(data)}"):
+        assert pillow_jxl.Decoder()(truncated)[2] == pixels
+    with pytest.raises(ValueError, match=f"byte {len(data)}"):
+        pillow_jxl.Decoder(strict=True)(truncated)
+
+    with pytest.warns(pillow_jxl.JxlWarning, match="Exif"):
+        _, info, _, _, boxes = pillow_jxl.Decoder()(invalid_exif)
+    assert info.exif is None
+    assert boxes[-1].box_type == b"Exif"
+    with pytest.raises(ValueError, match=f'"Exif" at byte {len(data)}: Exif data'):
+        pillow_jxl.Decoder(strict=True)(invalid_exif)
+
+    # errors about a box give its offset
+    with pytest.warns(pillow_jxl.JxlWarning, match=f'"brob" at byte {len(data)}'):
+        pillow_jxl.Decoder()(invalid_brob)
+    with pytest.raises(RuntimeError, match=f'"brob" at byte {len(data)}'):
+        pillow_jxl.Decoder(strict=True)(invalid_brob)
+
+    with pytest.raises(ValueError, match=f"byte {len(data)}"):
+        pillow_jxl.JxlContainer(truncated)
+    with pytest.warns(pillow_jxl.JxlWarning):
+        container = pillow_jxl.JxlContainer(truncated, strict=False)
+    assert container.tobytes() == data
+
+    with pytest.warns(pillow_jxl.JxlWarning):
+        with Image.open(BytesIO(truncated)) as reopened:
+            reopened.load()
+
+
+def test_logging(caplog):
+    img = Image.open("test/images/sample.png").convert("RGB")
+    data = pillow_jxl.Encoder(mode="RGB", use_container=True)(
+        img.tobytes(), img.width, img.height, jpeg_encode=False
+    )
+
+    with caplog.at_level(logging.DEBUG, logger="pillow_jxl"):
+        pillow_jxl.Decoder()(data)
+        with Image.open(BytesIO(data)) as reopened:
+            reopened.load()
+    messages = [r.getMessage() for r in caplog.records]
+    assert any(m.startswith(f"Decoded {img.width}x{img.height} RGB") for m in messages)
+    assert any(m.startswith("Opened stream") for m in messages)
+    assert all(r.name.startswith("pillow_jxl") for r in caplog.records)
+
+    caplog.clear()
+    with caplog.at_level(logging.WARNING, logger="pillow_jxl"):
+        pillow_jxl.Encoder(mode="RGB")(
+            img.tobytes(), img.width, img.height, jpeg_encode=False
+        )
+        with pytest.warns(pillow_jxl.JxlWarning):
+            pillow_jxl.Decoder()(data + _box(b"Exif", b"junk"))
+    assert [r.levelno for r in caplog.records] == [logging.WARNING]
+    assert "Exif" in caplog.records[0].getMessage()
+
+    # batch workers log with the level looked up when the batch started
+    caplog.clear()
+    with caplog.at_level(logging.DEBUG, logger="pillow_jxl"):
+        pillow_jxl.Decoder().decode_many([data, data], max_workers=2)
+    messages = [r.getMessage() for r in caplog.records]
+    assert sum(m.startswith("Decoded") for m in messages) == 2
+    caplog.clear()
+    pillow_jxl.Decoder().decode_many([data, data], max_workers=2)
+    assert not caplog.records
+
+
+def test_codestream_level():
+    img = Image.open("test/images/sample.png").convert("RGB")
+    args = (img.tobytes(), img.width, img.height)
+
+    data = pillow_jxl.Encoder(mode="RGB")(*args, jpeg_encode=False)
+    _, info, _, _, _ = pillow_jxl.Decoder()(data)
+    assert (info.level, info.exceeds_level5) == (5, False)
+
+    level5 = pillow_jxl.Encoder(mode="RGB", level=5)(*args, jpeg_encode=False)
+    assert level5[:2] == b"\xff\x0a"
+    assert pillow_jxl.Decoder()(level5)[1].level == 5
+
+    level10 = pillow_jxl.Encoder(mode="RGB", level=10)(*args, jpeg_encode=False)
+    assert pillow_jxl.JxlContainer(level10).find("jxll").data == b"\x0a"
+    _, info, decoded, _, _ = pillow_jxl.Decoder()(level10)
+    assert (info.level, info.exceeds_level5) == (10, False)
+    assert decoded == pillow_jxl.Decoder()(data)[2]
+
+    with tempfile.TemporaryDirectory() as tmp:
+        path = os.path.join(tmp, "level10.jxl")
+        img.save(path, level=10, lossless=True)
+        with Image.open(path) as reopened:
+            assert reopened._jxlinfo.level == 10
+            assert np.array_equal(np.array(reopened), np.array(img))
+
+    # 32-bit float samples are beyond level 5
+    floats = np.random.rand(16, 16).astype(np.float32)
+    enc = pillow_jxl.Encoder(mode="F", lossless=True)
+    _, info, _, _, _ = pillow_jxl.Decoder()(enc(floats.tobytes(), 16, 16, False))
+    assert (info.level, info.exceeds_level5) == (10, True)
+    with pytest.raises(ValueError, match="level 5"):
+        pillow_jxl.Encoder(mode="F", level=5)(floats.tobytes(), 16, 16, False)
+    with pytest.raises(ValueError):
+        pillow_jxl.Encoder(mode="RGB", level=7)